@@ -0,0 +1,94 @@
+use std::rc::Rc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use macroquad::{prelude::*, rand::srand};
+use rwfc::{Grid, WfcPreprocessor, process, overlapping_model::{OverlappingPreprocessor, ColorPattern}, utils::AdjacencyData};
+
+const SEED: u64 = 42;
+
+fn maze_rules() -> (Rc<ColorPattern>, Rc<AdjacencyData>) {
+  let image = Image::from_file_with_format(include_bytes!("../assets/pat-maze.png"), None);
+  let processor = OverlappingPreprocessor::new(3, true, true, false);
+  let (patterns, adjacency_rules) = process(&processor, &image).expect("image should be processed");
+  (Rc::new(patterns), Rc::new(adjacency_rules))
+}
+
+fn solve(c: &mut Criterion) {
+  let (patterns, adjacency_rules) = maze_rules();
+
+  let mut group = c.benchmark_group("run_to_completion");
+  for size in [8, 16, 32] {
+    // elements are cells, so criterion reports time per cell
+    group.throughput(Throughput::Elements((size * size) as u64));
+    group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, size| {
+      b.iter(|| {
+        srand(SEED);
+        let mut grid = Grid::new(*size, *size, &adjacency_rules, &patterns);
+        grid.run_to_completion(100_000)
+      });
+    });
+  }
+  group.finish();
+}
+
+// the same large grid observed by scanning every cell vs popping the entropy heap
+fn observe(c: &mut Criterion) {
+  let (patterns, adjacency_rules) = maze_rules();
+  let size = 64;
+
+  let mut group = c.benchmark_group("observe");
+  group.sample_size(10);
+  for (name, use_entropy_heap) in [("scan", false), ("heap", true)] {
+    group.bench_function(name, |b| {
+      b.iter(|| {
+        srand(SEED);
+        let mut grid = Grid::new(size, size, &adjacency_rules, &patterns).with_entropy_heap(use_entropy_heap);
+        grid.run_to_completion(1_000_000)
+      });
+    });
+  }
+  group.finish();
+}
+
+// pattern extraction with rotations from a 64x64 source with plenty of distinct windows
+fn extract(c: &mut Criterion) {
+  let palette = [BLACK, WHITE, RED, GREEN, BLUE];
+  let mut image = Image::gen_image_color(64, 64, BLACK);
+  for x in 0..64u32 {
+    for y in 0..64u32 {
+      image.set_pixel(x, y, palette[((x * 7 + y * 13 + x * y) % 5) as usize]);
+    }
+  }
+  let processor = OverlappingPreprocessor::new(3, true, true, true);
+
+  let mut group = c.benchmark_group("extract");
+  group.sample_size(10);
+  group.bench_function("overlapping_rotated", |b| {
+    b.iter(|| processor.extract_images(&image).expect("image should be processed"));
+  });
+  group.finish();
+}
+
+// adjacency rules for the same patterns computed on thread pools of increasing size
+#[cfg(feature = "parallel")]
+fn adjacency(c: &mut Criterion) {
+  let image = Image::from_file_with_format(include_bytes!("../assets/pat-maze.png"), None);
+  let processor = OverlappingPreprocessor::new(3, true, true, true);
+  let images = processor.extract_images(&image).expect("image should be processed");
+
+  let mut group = c.benchmark_group("adjacency_threads");
+  group.sample_size(10);
+  for threads in [1, 2, 4, 8] {
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().expect("thread pool should be built");
+    group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, _| {
+      b.iter(|| pool.install(|| processor.create_adjacency_rules(&images)));
+    });
+  }
+  group.finish();
+}
+
+#[cfg(feature = "parallel")]
+criterion_group!(benches, solve, observe, extract, adjacency);
+#[cfg(not(feature = "parallel"))]
+criterion_group!(benches, solve, observe, extract);
+criterion_main!(benches);
@@ -0,0 +1,80 @@
+use std::{fs, process::ExitCode, rc::Rc, time::{SystemTime, UNIX_EPOCH}};
+
+use clap::{Parser, ValueEnum};
+use macroquad::prelude::*;
+use rwfc::{Grid, WfcPreprocessor, process, overlapping_model::OverlappingPreprocessor, tile_model::TileProcessor};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Model {
+  Tile,
+  Overlapping,
+}
+
+// generates a map without opening a window, the defaults are the ones of the demo in `main.rs`
+#[derive(Parser, Debug)]
+#[command(name = "rwfc-cli", about = "Generates a map from a sample image with wave function collapse")]
+struct Args {
+  #[arg(long, value_enum, default_value = "tile")]
+  model: Model,
+  #[arg(long, default_value = "assets/tiles-standard.png")]
+  input: String,
+  #[arg(long, default_value_t = 100)]
+  width: usize,
+  #[arg(long, default_value_t = 50)]
+  height: usize,
+  #[arg(long, help = "defaults to the current time, like the demo")]
+  seed: Option<u64>,
+  // `.png` writes the rasterized grid, anything else the pattern index of every cell, one row per
+  // line
+  #[arg(long, default_value = "export.png", help = "a .png, or a text file of pattern indexes")]
+  output: String,
+  #[arg(long, default_value_t = 32, help = "size of a tile in the sample, for the tile model")]
+  tile_size: u16,
+  #[arg(long, default_value_t = 3, help = "size of an extracted pattern, for the overlapping model")]
+  n: isize,
+  #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+  rotate: bool,
+  #[arg(long, default_value_t = 0, help = "times generation starts over after an unresolvable contradiction")]
+  restarts: usize,
+}
+
+fn main() -> ExitCode {
+  let args = Args::parse();
+  let bytes = match fs::read(&args.input) {
+    Ok(bytes) => bytes,
+    Err(e) => {
+      eprintln!("can't read {}: {}", args.input, e);
+      return ExitCode::FAILURE;
+    },
+  };
+  let image = Image::from_file_with_format(&bytes, None);
+  let result = match args.model {
+    Model::Tile => generate(&TileProcessor::new(args.tile_size as f32, args.tile_size as f32, args.rotate), &image, &args),
+    Model::Overlapping => generate(&OverlappingPreprocessor::new(args.n, true, true, args.rotate), &image, &args),
+  };
+  match result {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(e) => {
+      eprintln!("{}", e);
+      ExitCode::FAILURE
+    },
+  }
+}
+
+fn generate<P: WfcPreprocessor>(processor: &P, image: &Image, args: &Args) -> Result<(), String> {
+  let (patterns, adjacency_rules) = process(processor, image).map_err(|e| format!("can't process {}: {:?}", args.input, e))?;
+  let seed = args.seed.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs());
+  let mut grid = Grid::new_with_seed(args.width, args.height, &Rc::new(adjacency_rules), &Rc::new(patterns), seed);
+  grid.generate(args.restarts).map_err(|e| format!("generation failed with seed {}: {:?}", seed, e))?;
+
+  if args.output.ends_with(".png") {
+    grid.export_png(&args.output).map_err(|e| format!("can't export: {:?}", e))?;
+  } else {
+    let cells = grid.to_indices().ok_or("grid isn't finished")?;
+    let rows: Vec<String> = cells.chunks(args.width)
+      .map(|row| row.iter().map(|p| p.to_string()).collect::<Vec<String>>().join(" "))
+      .collect();
+    fs::write(&args.output, rows.join("\n") + "\n").map_err(|e| format!("can't write {}: {}", args.output, e))?;
+  }
+  Ok(())
+}
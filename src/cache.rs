@@ -0,0 +1,191 @@
+use std::{fs, io, path::Path};
+
+use macroquad::prelude::*;
+use serde::{Serialize, Deserialize};
+
+use crate::{WfcPreprocessor, PreprocessError, utils::{AdjacencyData, compact_adjacency}};
+
+type DirectionList = Vec<((isize, isize), Vec<usize>)>;
+
+// bumped whenever `CachedRules` or what goes into the cache key changes, older cache files are
+// then misses
+const CACHE_VERSION: u32 = 1;
+
+// textures can't be stored, so the extracted pattern images are cached and the patterns are
+// rebuilt from them, adjacency keys are kept as pairs since JSON maps need string keys
+#[derive(Serialize, Deserialize)]
+struct CachedRules {
+  version: u32,
+  images: Vec<(u16, u16, Vec<u8>)>,
+  adjacency: Vec<DirectionList>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RulesFile {
+  pattern_count: usize,
+  weights: Vec<f32>,
+  adjacency: Vec<DirectionList>,
+}
+
+fn to_direction_lists(adjacency_rules: &AdjacencyData) -> Vec<DirectionList> {
+  adjacency_rules.iter()
+    .map(|adjacencies| {
+      let mut directions: DirectionList = adjacencies.iter().map(|(d, targets)| (*d, targets.clone())).collect();
+      directions.sort();
+      directions
+    })
+    .collect()
+}
+
+fn from_direction_lists(adjacency: Vec<DirectionList>) -> AdjacencyData {
+  adjacency.into_iter().map(|adjacencies| adjacencies.into_iter().collect()).collect()
+}
+
+// writes adjacency rules and pattern weights (e.g. from `process_weighted`) as JSON, so later runs
+// only need `create_patterns` on the extracted images instead of computing the rules again
+pub fn save_rules(path: &Path, adjacency_rules: &AdjacencyData, weights: &[f32]) -> io::Result<()> {
+  let rules = RulesFile {
+    pattern_count: adjacency_rules.len(),
+    weights: weights.to_vec(),
+    adjacency: to_direction_lists(adjacency_rules),
+  };
+  fs::write(path, serde_json::to_string(&rules).expect("rules should serialize"))
+}
+
+// rules and weights written by `save_rules`, files referring to patterns past their own pattern
+// count are rejected as invalid data
+pub fn load_rules(path: &Path) -> io::Result<(AdjacencyData, Vec<f32>)> {
+  let data = fs::read_to_string(path)?;
+  let rules: RulesFile = serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+  let consistent = rules.adjacency.len() == rules.pattern_count
+    && rules.weights.len() == rules.pattern_count
+    && rules.adjacency.iter().flatten().flat_map(|(_, targets)| targets).all(|p| *p < rules.pattern_count);
+  if !consistent {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, "rules refer to patterns they don't define"));
+  }
+  Ok((from_direction_lists(rules.adjacency), rules.weights))
+}
+
+// 64-bit FNV-1a, unlike `DefaultHasher` it gives the same hash on every platform and toolchain
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+  bytes.iter().fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3))
+}
+
+// the cache file name for `image` processed with `config_key`, lengths go in ahead of the
+// variable-length parts so different splits of the same bytes don't collide
+fn cache_key(image: &Image, config_key: &str) -> String {
+  let mut hash = 0xcbf2_9ce4_8422_2325;
+  hash = fnv1a(hash, &CACHE_VERSION.to_le_bytes());
+  hash = fnv1a(hash, &image.width.to_le_bytes());
+  hash = fnv1a(hash, &image.height.to_le_bytes());
+  hash = fnv1a(hash, &(image.bytes.len() as u64).to_le_bytes());
+  hash = fnv1a(hash, &image.bytes);
+  hash = fnv1a(hash, &(config_key.len() as u64).to_le_bytes());
+  hash = fnv1a(hash, config_key.as_bytes());
+  format!("v{}-{:016x}.json", CACHE_VERSION, hash)
+}
+
+// `process` with the expensive extraction and adjacency steps cached in `cache_dir`, keyed by a
+// hash of the image and the processor config, unreadable cache files or ones written by another
+// cache version count as a miss
+pub fn process_cached<P: WfcPreprocessor>(processor: &P, image: &Image, cache_dir: &Path) -> Result<(P::Pattern, AdjacencyData), PreprocessError> {
+  let cache_file = cache_dir.join(cache_key(image, &processor.config_key()));
+
+  let cached = fs::read_to_string(&cache_file).ok()
+    .and_then(|data| serde_json::from_str::<CachedRules>(&data).ok())
+    .filter(|cached| cached.version == CACHE_VERSION);
+  if let Some(cached) = cached {
+    let images: Vec<Image> = cached.images.into_iter().map(|(width, height, bytes)| Image { bytes, width, height }).collect();
+    let adjacency_rules = from_direction_lists(cached.adjacency);
+    return Ok((processor.create_patterns(&images), adjacency_rules));
+  }
+
+  let images = processor.extract_images(image)?;
+  let patterns = processor.create_patterns(&images);
+  let mut adjacency_rules = processor.create_adjacency_rules(&images);
+  compact_adjacency(&mut adjacency_rules, true);
+
+  let cached = CachedRules {
+    version: CACHE_VERSION,
+    images: images.iter().map(|img| (img.width, img.height, img.bytes.clone())).collect(),
+    adjacency: to_direction_lists(&adjacency_rules),
+  };
+  fs::create_dir_all(cache_dir).map_err(PreprocessError::Cache)?;
+  fs::write(&cache_file, serde_json::to_string(&cached).expect("cached rules should serialize")).map_err(PreprocessError::Cache)?;
+
+  Ok((patterns, adjacency_rules))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::Cell;
+
+  use super::*;
+  use crate::{Drawable, overlapping_model::OverlappingPreprocessor};
+
+  // the overlapping model, counting how often adjacency rules get computed
+  struct Counting(OverlappingPreprocessor, Cell<usize>);
+
+  impl WfcPreprocessor for Counting {
+    type Pattern = <OverlappingPreprocessor as WfcPreprocessor>::Pattern;
+    fn extract_images(&self, image: &Image) -> Result<Vec<Image>, PreprocessError> {
+      self.0.extract_images(image)
+    }
+    fn create_patterns(&self, images: &[Image]) -> Self::Pattern {
+      self.0.create_patterns(images)
+    }
+    fn create_adjacency_rules(&self, images: &[Image]) -> AdjacencyData {
+      self.1.set(self.1.get() + 1);
+      self.0.create_adjacency_rules(images)
+    }
+    fn config_key(&self) -> String {
+      self.0.config_key()
+    }
+  }
+
+  #[test]
+  fn second_run_hits_the_cache() {
+    let cache_dir = std::env::temp_dir().join(format!("rwfc-cache-test-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&cache_dir);
+    let mut image = Image::gen_image_color(4, 4, RED);
+    image.set_pixel(1, 1, BLUE);
+    let processor = Counting(OverlappingPreprocessor::new(2, true, true, false), Cell::new(0));
+    let (patterns, computed) = process_cached(&processor, &image, &cache_dir).unwrap();
+    assert_eq!(fs::read_dir(&cache_dir).unwrap().count(), 1);
+    let (cached_patterns, cached) = process_cached(&processor, &image, &cache_dir).unwrap();
+    assert_eq!(processor.1.get(), 1);
+    assert_eq!(cached, computed);
+    assert_eq!(cached_patterns.len(), patterns.len());
+    let _ = fs::remove_dir_all(&cache_dir);
+  }
+
+  #[test]
+  fn cache_keys_are_stable() {
+    let mut image = Image::gen_image_color(4, 4, RED);
+    image.set_pixel(1, 1, BLUE);
+    // the reference FNV-1a 64 hashes of "" and "a"
+    assert_eq!(fnv1a(0xcbf2_9ce4_8422_2325, b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(fnv1a(0xcbf2_9ce4_8422_2325, b"a"), 0xaf63_dc4c_8601_ec8c);
+    let key = cache_key(&image, "overlapping n=2");
+    assert!(key.starts_with("v1-") && key.ends_with(".json"));
+    assert_eq!(key, cache_key(&image, "overlapping n=2"));
+    assert_ne!(key, cache_key(&image, "overlapping n=3"));
+    image.set_pixel(2, 2, BLUE);
+    assert_ne!(key, cache_key(&image, "overlapping n=2"));
+  }
+
+  #[test]
+  fn saved_rules_load_back() {
+    let path = std::env::temp_dir().join(format!("rwfc-rules-test-{}.json", std::process::id()));
+    let rules = crate::tests::three_bands();
+    let weights = [0.5, 0.25, 0.25];
+    save_rules(&path, &rules, &weights).unwrap();
+    let (loaded, loaded_weights) = load_rules(&path).unwrap();
+    assert_eq!(loaded, *rules);
+    assert_eq!(loaded_weights, weights);
+    // a rule pointing past the patterns the file defines
+    fs::write(&path, r#"{"pattern_count":1,"weights":[1.0],"adjacency":[[[[1,0],[1]]]]}"#).unwrap();
+    assert_eq!(load_rules(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    let _ = fs::remove_file(&path);
+  }
+}
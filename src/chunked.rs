@@ -0,0 +1,199 @@
+use std::{collections::HashMap, rc::Rc};
+
+#[cfg(feature = "render")]
+use crate::TILE_SIZE;
+use crate::{Drawable, Grid, WfcError, utils::{AdjacencyData, AdjacencyBits, N_INDEXES, adjacency_to_bitsets, index_from_xy}};
+
+// an endless world made of `chunk_width` x `chunk_height` chunks generated on demand. A new chunk
+// is a `Grid` whose border cells are constrained by the collapsed edge cells of the chunks already
+// next to it, so seams follow the adjacency rules. Chunks are addressed by chunk coordinates,
+// cells by world coordinates (chunk * chunk size + cell)
+pub struct ChunkedGrid<P: Drawable + Clone> {
+  chunk_width: usize,
+  chunk_height: usize,
+  adjacency_rules: Rc<AdjacencyData>,
+  // built once, every chunk's grid shares them
+  adjacency_bits: Rc<AdjacencyBits>,
+  patterns: Rc<P>,
+  seed: u64,
+  max_restarts: usize,
+  chunks: HashMap<(i32, i32), Vec<usize>>,
+}
+
+impl<P: Drawable + Clone> ChunkedGrid<P> {
+  // every chunk's grid is seeded from `seed` and its coordinates, so the same chunks generated in
+  // the same order come out the same
+  pub fn new(chunk_width: usize, chunk_height: usize, adjacency_rules: &Rc<AdjacencyData>, patterns: &Rc<P>, seed: u64) -> Self {
+    Self {
+      chunk_width,
+      chunk_height,
+      adjacency_rules: Rc::clone(adjacency_rules),
+      adjacency_bits: Rc::new(adjacency_to_bitsets(adjacency_rules, patterns.len())),
+      patterns: Rc::clone(patterns),
+      seed,
+      max_restarts: 10,
+      chunks: HashMap::new(),
+    }
+  }
+
+  // how often a chunk that runs into a contradiction it can't backtrack is generated again (see
+  // `Grid::generate`), only that chunk is, the rest of the world stays as it is
+  pub fn with_max_restarts(mut self, max_restarts: usize) -> Self {
+    self.max_restarts = max_restarts;
+    self
+  }
+
+  // pattern of every cell of the chunk row by row, `None` until it's generated
+  pub fn chunk(&self, cx: i32, cy: i32) -> Option<&[usize]> {
+    self.chunks.get(&(cx, cy)).map(|cells| cells.as_slice())
+  }
+
+  pub fn chunk_count(&self) -> usize {
+    self.chunks.len()
+  }
+
+  // pattern at world x, y, `None` while its chunk isn't generated
+  pub fn get(&self, x: i64, y: i64) -> Option<usize> {
+    let (width, height) = (self.chunk_width as i64, self.chunk_height as i64);
+    let cells = self.chunks.get(&(x.div_euclid(width) as i32, y.div_euclid(height) as i32))?;
+    Some(cells[index_from_xy(x.rem_euclid(width) as usize, y.rem_euclid(height) as usize, self.chunk_width)])
+  }
+
+  // generates the chunk unless it already exists. A contradiction that neither backtracking nor
+  // the restarts resolve leaves the chunk missing and is returned, its neighbours are kept
+  pub fn generate_chunk(&mut self, cx: i32, cy: i32) -> Result<&[usize], WfcError> {
+    if !self.chunks.contains_key(&(cx, cy)) {
+      let cells = self.solve_chunk(cx, cy)?;
+      self.chunks.insert((cx, cy), cells);
+    }
+    Ok(&self.chunks[&(cx, cy)])
+  }
+
+  // a seam contradiction (the constrained border can't be propagated) re-runs just this chunk
+  // with a seed derived from the last one, at most `max_restarts` times
+  fn solve_chunk(&self, cx: i32, cy: i32) -> Result<Vec<usize>, WfcError> {
+    let mut seed = self.chunk_seed(cx, cy);
+    let mut restarts = 0;
+    loop {
+      match self.constrained_chunk(cx, cy, seed) {
+        Ok(mut grid) => {
+          grid.generate(self.max_restarts)?;
+          return Ok(grid.to_indices().expect("generated grid should be finished"));
+        },
+        Err(error) if restarts == self.max_restarts => return Err(error),
+        Err(_) => {},
+      }
+      restarts += 1;
+      seed = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15).wrapping_add(restarts as u64);
+    }
+  }
+
+  // the chunk's grid with its border cells constrained by the collapsed edge cells of the chunks
+  // already next to it
+  fn constrained_chunk(&self, cx: i32, cy: i32, seed: u64) -> Result<Grid<P>, WfcError> {
+    let (width, height) = (self.chunk_width, self.chunk_height);
+    let mut grid: Grid<P> = Grid::new_shared(width, height, &self.adjacency_rules, &self.adjacency_bits, &self.patterns, seed);
+    for (dx, dy) in N_INDEXES {
+      let Some(neighbour) = self.chunks.get(&(cx + dx as i32, cy + dy as i32)) else {
+        continue;
+      };
+      let border: Vec<(usize, usize)> = match (dx, dy) {
+        (1, 0) => (0..height).map(|y| (width - 1, y)).collect(),
+        (-1, 0) => (0..height).map(|y| (0, y)).collect(),
+        (0, 1) => (0..width).map(|x| (x, height - 1)).collect(),
+        _ => (0..width).map(|x| (x, 0)).collect(),
+      };
+      for (x, y) in border {
+        // the neighbour chunk's cell right across the seam
+        let across = neighbour[index_from_xy((x as isize + dx).rem_euclid(width as isize) as usize, (y as isize + dy).rem_euclid(height as isize) as usize, width)];
+        let allowed: Vec<usize> = (0..self.patterns.len())
+          .filter(|p| self.adjacency_rules[*p].get(&(dx, dy)).is_some_and(|neighbours| neighbours.contains(&across)))
+          .collect();
+        grid.constrain_cell(x, y, &allowed)?;
+      }
+    }
+    Ok(grid)
+  }
+
+  fn chunk_seed(&self, cx: i32, cy: i32) -> u64 {
+    self.seed ^ (cx as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ (cy as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f)
+  }
+
+  // generates every missing chunk within `radius` chunks (Chebyshev) of `center`, nearest first so
+  // new chunks grow outwards from the ones already there
+  pub fn generate_around(&mut self, center: (i32, i32), radius: i32) -> Result<(), WfcError> {
+    let mut missing: Vec<(i32, i32)> = (-radius..=radius)
+      .flat_map(|dy| (-radius..=radius).map(move |dx| (center.0 + dx, center.1 + dy)))
+      .filter(|chunk| !self.chunks.contains_key(chunk))
+      .collect();
+    missing.sort_by_key(|(cx, cy)| ((cx - center.0).abs().max((cy - center.1).abs()), (cx - center.0).abs() + (cy - center.1).abs()));
+    for (cx, cy) in missing {
+      self.generate_chunk(cx, cy)?;
+    }
+    Ok(())
+  }
+
+  // drops chunks further than `radius` chunks (Chebyshev) from `center`, gives how many. A dropped
+  // chunk generated again is fitted to whatever surrounds it then, so it may come out different
+  pub fn evict_outside(&mut self, center: (i32, i32), radius: i32) -> usize {
+    let before = self.chunks.len();
+    self.chunks.retain(|(cx, cy), _| (cx - center.0).abs() <= radius && (cy - center.1).abs() <= radius);
+    before - self.chunks.len()
+  }
+
+  // every generated chunk at its world position, TILE_SIZE per cell, meant to be drawn through a
+  // camera (e.g. a `View`) following the area being generated
+  #[cfg(feature = "render")]
+  pub fn draw(&self) {
+    for ((cx, cy), cells) in self.chunks.iter() {
+      let origin_x = *cx as f32 * self.chunk_width as f32 * TILE_SIZE;
+      let origin_y = *cy as f32 * self.chunk_height as f32 * TILE_SIZE;
+      for (index, pattern) in cells.iter().enumerate() {
+        let (x, y) = (index % self.chunk_width, index / self.chunk_width);
+        self.patterns.draw(origin_x + x as f32 * TILE_SIZE, origin_y + y as f32 * TILE_SIZE, *pattern);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests::{Ids, three_bands};
+
+  #[test]
+  fn neighbouring_chunks_agree_along_their_seams() {
+    let rules = three_bands();
+    for seed in 0..5 {
+      let mut world = ChunkedGrid::new(6, 4, &rules, &Rc::new(Ids(3)), seed);
+      world.generate_around((0, 0), 1).unwrap();
+      assert_eq!(world.chunk_count(), 9);
+      // every pair of horizontal and vertical neighbours across the 3x3 chunks, seams included
+      for y in -4..8 {
+        for x in -6..12 {
+          for (dx, dy) in [(1, 0), (0, 1)] {
+            let (Some(p), Some(n)) = (world.get(x, y), world.get(x + dx, y + dy)) else {
+              continue;
+            };
+            assert!(rules[p][&(dx as isize, dy as isize)].contains(&n), "seed {}: {}, {}", seed, x, y);
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn the_same_chunk_comes_out_the_same() {
+    let rules = three_bands();
+    let patterns = Rc::new(Ids(3));
+    let mut first = ChunkedGrid::new(5, 5, &rules, &patterns, 7);
+    let mut second = ChunkedGrid::new(5, 5, &rules, &patterns, 7);
+    for (cx, cy) in [(0, 0), (1, 0), (1, 1)] {
+      assert_eq!(first.generate_chunk(cx, cy).unwrap(), second.generate_chunk(cx, cy).unwrap());
+    }
+    // an evicted chunk generated again with the same surroundings is the same as before
+    let before = first.chunk(0, 0).unwrap().to_vec();
+    assert_eq!(first.evict_outside((5, 5), 0), 3);
+    assert_eq!(first.generate_chunk(0, 0).unwrap(), before.as_slice());
+  }
+}
@@ -0,0 +1,2680 @@
+use std::{cell::RefCell, cmp::Ordering, collections::{HashMap, BTreeSet, BinaryHeap, VecDeque}, rc::Rc, time::{Duration, Instant}};
+
+use fixedbitset::FixedBitSet;
+use macroquad::{prelude::*, rand};
+#[cfg(feature = "render")]
+use macroquad::telemetry::ZoneGuard;
+use utils::{AdjacencyData, AdjacencyBits, PatternIndex, SeededRng, xy_from_index, index_from_xy, weighted_choice, compact_adjacency, validate_adjacency, pattern_set, adjacency_to_bitsets, N_INDEXES};
+
+pub mod utils;
+pub mod neighbourhood;
+pub mod overlapping_model;
+pub mod tile_model;
+pub mod socket_model;
+pub mod chunked;
+#[cfg(feature = "render")]
+pub mod view;
+#[cfg(feature = "serde")]
+pub mod cache;
+#[cfg(feature = "serde")]
+pub mod state;
+
+pub use neighbourhood::{Neighbourhood, Square4, Square8, Hex};
+#[cfg(feature = "serde")]
+pub use cache::{process_cached, save_rules, load_rules};
+
+pub const TILE_SIZE: f32 = 16.;
+#[cfg(feature = "render")]
+const GRID_OFFSET: f32 = 0.;
+const HISTORY_LENGHT: usize = 20;
+// entropies closer than this are treated as equal and the tie is broken at random
+const ENTROPY_EPSILON: f64 = 1e-9;
+
+// without `render` there's no profiler to report the zones to
+#[cfg(not(feature = "render"))]
+struct ZoneGuard;
+
+#[cfg(not(feature = "render"))]
+impl ZoneGuard {
+  fn new(_name: &str) -> Self {
+    ZoneGuard
+  }
+}
+
+// only `rasterize` and `len` are needed to generate, the window drawing methods exist with the
+// `render` feature
+pub trait Drawable {
+  #[cfg(feature = "render")]
+  fn draw(&self, x: f32, y: f32, idx: usize);
+  // writes the same TILE_SIZE block `draw` puts on screen into `target` at pixel x, y
+  fn rasterize(&self, target: &mut Image, x: u32, y: u32, idx: usize);
+  // preview of a not yet collapsed cell from its remaining (pattern, weight) options
+  #[cfg(feature = "render")]
+  fn draw_superposition(&self, _x: f32, _y: f32, _options: &[(usize, f64)]) {}
+  fn len(&self) -> usize;
+  fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+#[derive(Debug)]
+pub enum PreprocessError {
+  ImageTooSmall { width: u16, height: u16, n: u16 },
+  // the sheet doesn't hold one tile per socket description
+  SocketCount { tiles: usize, sockets: usize },
+  Cache(std::io::Error),
+}
+
+pub trait WfcPreprocessor {
+  type Pattern: Drawable + Clone;
+  fn extract_images(&self, image: &Image) -> Result<Vec<Image>, PreprocessError>;
+  fn create_patterns(&self, images: &[Image]) -> Self::Pattern;
+  fn create_adjacency_rules(&self, images: &[Image]) -> AdjacencyData;
+  // the extracted images along with how often each occurs in the sample, uniform unless the
+  // model counts occurrences
+  fn extract_weighted_images(&self, image: &Image) -> Result<(Vec<Image>, Vec<f32>), PreprocessError> {
+    let images = self.extract_images(image)?;
+    let weights = vec![1.; images.len()];
+    Ok((images, weights))
+  }
+  // per-image weights mapped onto the patterns `create_patterns` builds from `images`
+  fn pattern_weights(&self, _images: &[Image], image_weights: Vec<f32>) -> Vec<f32> {
+    image_weights
+  }
+  // an image of every pattern `create_patterns` builds from `images`, in pattern order. For the
+  // overlapping model that's the whole N x N window rather than the pixel a pattern draws
+  fn pattern_images(&self, images: &[Image]) -> Vec<Image> {
+    images.to_vec()
+  }
+  // every setting that changes the extracted patterns or rules, used to key cached results
+  fn config_key(&self) -> String;
+}
+
+pub fn process<P: WfcPreprocessor>(processor: &P, image: &Image) -> Result<(P::Pattern, AdjacencyData), PreprocessError> {
+  let images = processor.extract_images(image)?;
+  let patterns = processor.create_patterns(&images);
+  let mut adjacency_rules = processor.create_adjacency_rules(&images);
+  compact_adjacency(&mut adjacency_rules, true);
+
+  Ok((patterns, adjacency_rules))
+}
+
+// `process` plus a weight per pattern, meant for `Grid::with_pattern_weights`
+pub fn process_weighted<P: WfcPreprocessor>(processor: &P, image: &Image) -> Result<(P::Pattern, AdjacencyData, Vec<f32>), PreprocessError> {
+  let (images, image_weights) = processor.extract_weighted_images(image)?;
+  let patterns = processor.create_patterns(&images);
+  let mut adjacency_rules = processor.create_adjacency_rules(&images);
+  compact_adjacency(&mut adjacency_rules, true);
+  let weights = processor.pattern_weights(&images, image_weights);
+
+  Ok((patterns, adjacency_rules, weights))
+}
+
+// the image of each pattern `process` would produce next to its index, for looking at what the
+// preprocessor extracted along with the adjacency rules
+pub fn inspect_patterns<P: WfcPreprocessor>(processor: &P, image: &Image) -> Result<Vec<(usize, Image)>, PreprocessError> {
+  let images = processor.extract_images(image)?;
+  Ok(processor.pattern_images(&images).into_iter().enumerate().collect())
+}
+
+#[cfg(feature = "render")]
+pub fn process_texture<P: WfcPreprocessor>(processor: &P, texture: &Texture2D) -> Result<(P::Pattern, AdjacencyData), PreprocessError> {
+  process(processor, &texture.get_texture_data())
+}
+
+// problems in a hand-written rules file, `line` counts from 1
+#[derive(Debug, PartialEq, Eq)]
+pub enum RulesError {
+  Syntax { line: usize },
+  OutOfRange { line: usize, pattern: usize },
+  Asymmetric { pattern: usize, direction: (isize, isize), neighbour: usize },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WfcError {
+  // the cell at x, y has no options left, `direction` points from it to the neighbour whose
+  // constraint removed the last one, `None` when a pin or ban did
+  Contradiction { x: usize, y: usize, direction: Option<(isize, isize)> },
+  AlreadyCollapsed { x: usize, y: usize },
+  NotFinished,
+  // the image of the grid would be `width` x `height` pixels, more than an `Image` holds
+  ImageTooLarge { width: usize, height: usize },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StepOutcome {
+  Collapsed,
+  Finished,
+  Backtracked,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ObserveStrategy {
+  MinEntropy,
+  // the cell whose collapse is expected to remove the most options from its neighbours
+  MaxImpact,
+}
+
+// how collapse picks among the remaining options of a cell
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CollapseStrategy {
+  // uniformly, `pattern_weights` are ignored
+  Random,
+  // in proportion to `pattern_weights` and the cell, biome, histogram and filler weights
+  Weighted,
+  // always the lowest pattern id, with `TieBreak::FirstIndex` the whole solver runs without the
+  // RNG (only `require_at_least` still draws from it), e.g. for golden-file tests
+  LowestId,
+}
+
+// how observe picks among cells that are equally good candidates
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TieBreak {
+  // by the grid's RNG, as seeded when the grid was created
+  Random,
+  // the lowest cell index, no randomness involved
+  FirstIndex,
+  // by the grid's RNG reseeded with this seed
+  Deterministic(u64),
+}
+
+// a side of the grid, for `Grid::constrain_edge`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Edge {
+  Top,
+  Bottom,
+  Left,
+  Right,
+}
+
+// how `Grid::draw` puts the grid on screen
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RedrawMode {
+  // every cell is drawn every frame
+  Full,
+  // the grid is kept in a render target and only cells that changed since the last draw are
+  // drawn into it, over `background` so a cell that was undone doesn't keep its old tile
+  Incremental { background: Color },
+}
+
+// receives `(x, y, pattern)` of every cell that can no longer be undone
+pub type FinalizeCallback = Box<dyn FnMut(usize, usize, usize)>;
+
+// receives a snapshot of the grid after every collapse
+pub type StepCallback<I = u16> = Box<dyn FnMut(&GridSnapshot<I>)>;
+
+// what a step callback sees of the grid, enough to report progress or render a frame. `cells`
+// are stored in the grid's index type, `cell` reads one back as a pattern id
+pub struct GridSnapshot<'a, I: PatternIndex = u16> {
+  pub collapsed: usize,
+  pub total: usize,
+  pub index: usize,
+  pub width: usize,
+  pub height: usize,
+  pub cells: &'a [Option<I>],
+}
+
+impl<I: PatternIndex> GridSnapshot<'_, I> {
+  pub fn cell(&self, index: usize) -> Option<usize> {
+    self.cells[index].map(PatternIndex::pattern)
+  }
+}
+
+// entry of the observe heap, the greatest entry is the cell with the lowest entropy, then the
+// highest priority, the tiebreak stands in for the grid's `TieBreak` among equal cells
+struct EntropyEntry {
+  entropy: f64,
+  priority: f32,
+  tiebreak: u32,
+  idx: usize,
+}
+
+impl Ord for EntropyEntry {
+  fn cmp(&self, other: &Self) -> Ordering {
+    other.entropy.total_cmp(&self.entropy)
+      .then(self.priority.total_cmp(&other.priority))
+      .then(self.tiebreak.cmp(&other.tiebreak))
+  }
+}
+
+impl PartialOrd for EntropyEntry {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl PartialEq for EntropyEntry {
+  fn eq(&self, other: &Self) -> bool {
+    self.cmp(other) == Ordering::Equal
+  }
+}
+
+impl Eq for EntropyEntry {}
+
+// a step `unwind` can undo
+struct HistoryEntry {
+  // (pattern, cell) the step collapsed
+  choice: (usize, usize),
+  // every cell the step changed
+  updated_tiles: BTreeSet<usize>,
+  // options of the collapsed cell before the step
+  options: FixedBitSet,
+  // (cell, pattern) pairs backtracking ruled out while this is the latest step
+  excluded: Vec<(usize, usize)>,
+}
+
+// collapse weight of a candidate pattern keyed by (neighbour pattern, direction of that
+// neighbour, candidate pattern)
+type DirectionalWeights = HashMap<(usize, (isize, isize), usize), f64>;
+
+fn manhattan_distance(a: usize, b: usize, width: usize, height: usize, wrap: bool) -> usize {
+  let (ax, ay) = xy_from_index(a, width);
+  let (bx, by) = xy_from_index(b, width);
+  let axis = |a: usize, b: usize, size: usize| {
+    let d = a.abs_diff(b);
+    if wrap { d.min(size - d) } else { d }
+  };
+  axis(ax, bx, width) + axis(ay, by, height)
+}
+
+// `I` is the integer type collapsed cells are stored as, it has to hold every pattern id. The
+// default `u16` allows 65536 patterns, `u8` shrinks the grid further for small rulesets
+pub struct Grid<P: Drawable + Clone, I: PatternIndex = u16> {
+  width: usize,
+  height: usize,
+  cells: Vec<Option<I>>,
+  options: Vec<FixedBitSet>,
+  adjacency_rules: Rc<AdjacencyData>,
+  adjacency_bits: Rc<AdjacencyBits>,
+  patterns: Rc<P>,
+  history: VecDeque<HistoryEntry>,
+  history_length: Option<usize>,
+  // (cell, pattern) pairs backtracking ruled out below the oldest history entry, they're as
+  // permanent as the steps dropped from the history
+  excluded: Vec<(usize, usize)>,
+  cell_weights: Option<Vec<Vec<f64>>>,
+  entropy_heap: Option<BinaryHeap<EntropyEntry>>,
+  use_entropy_heap: bool,
+  spacing_rules: Vec<(Vec<usize>, Vec<usize>, usize)>,
+  observe_strategy: ObserveStrategy,
+  tie_break: TieBreak,
+  contradiction_counts: Vec<usize>,
+  last_contradiction: Option<(usize, Option<(isize, isize)>)>,
+  // cells propagation left without options, with the direction of the neighbour responsible
+  emptied_by: HashMap<usize, (isize, isize)>,
+  biomes: Option<(Vec<Vec<usize>>, f64)>,
+  banned: Vec<BTreeSet<usize>>,
+  neighbourhood: Rc<dyn Neighbourhood>,
+  target_histogram: Option<Vec<f64>>,
+  pattern_weights: Vec<f32>,
+  count_entropy: bool,
+  cell_priority: Option<(Vec<f32>, bool)>,
+  directional_weights: Option<DirectionalWeights>,
+  on_finalize: Option<FinalizeCallback>,
+  on_step: Option<StepCallback<I>>,
+  finalized: Vec<bool>,
+  // cells collapsed by propagation because a single pattern was left, rather than chosen by observe
+  forced: Vec<bool>,
+  minimum_counts: Vec<(usize, usize)>,
+  maximum_counts: Vec<(usize, usize)>,
+  collapse_strategy: CollapseStrategy,
+  filler: Option<(usize, f64)>,
+  rng: SeededRng,
+  propagation_radius: Option<usize>,
+  deferred_propagation: BTreeSet<usize>,
+  wrap: bool,
+  redraw_mode: RedrawMode,
+  // cells changed since the last incremental draw, only collected with `RedrawMode::Incremental`
+  dirty: RefCell<BTreeSet<usize>>,
+  #[cfg(feature = "render")]
+  render_target: RefCell<Option<RenderTarget>>,
+}
+
+impl<P: Drawable + Clone> Grid<P> {
+  // the ruleset is shared, grids created from the same `Rc`s never copy rules or patterns. The
+  // grid's own RNG is seeded from macroquad's global one, so `srand` still decides the output
+  pub fn new(width: usize, height: usize, adjacency_rules: &Rc<AdjacencyData>, patterns: &Rc<P>) -> Self {
+    let seed = (rand::rand() as u64) << 32 | rand::rand() as u64;
+    Self::new_with_seed(width, height, adjacency_rules, patterns, seed)
+  }
+
+  // every random decision of observe and collapse comes from an RNG seeded with `seed`, the same
+  // seed and ruleset always give the same output regardless of other grids or the global state
+  pub fn new_with_seed(width: usize, height: usize, adjacency_rules: &Rc<AdjacencyData>, patterns: &Rc<P>, seed: u64) -> Self {
+    Self::new_indexed(width, height, adjacency_rules, patterns, seed)
+  }
+
+  // `new`, checking the rules with `validate_adjacency` first so asymmetric rules (a data bug)
+  // are reported here rather than showing up as contradictions during generation
+  pub fn try_new(width: usize, height: usize, adjacency_rules: &Rc<AdjacencyData>, patterns: &Rc<P>) -> Result<Self, Vec<RulesError>> {
+    validate_adjacency(adjacency_rules)?;
+    Ok(Self::new(width, height, adjacency_rules, patterns))
+  }
+}
+
+impl<P: Drawable + Clone, I: PatternIndex> Grid<P, I> {
+  // `new_with_seed` for another index type, e.g. `Grid::<_, u8>::new_indexed(..)`
+  pub fn new_indexed(width: usize, height: usize, adjacency_rules: &Rc<AdjacencyData>, patterns: &Rc<P>, seed: u64) -> Self {
+    let adjacency_bits = Rc::new(adjacency_to_bitsets(adjacency_rules, patterns.len()));
+    Self::new_shared(width, height, adjacency_rules, &adjacency_bits, patterns, seed)
+  }
+
+  // `new_indexed` with the rules already converted to bitsets, grids generated from one ruleset
+  // (e.g. the chunks of a `ChunkedGrid`) share them instead of each converting the rules again
+  pub(crate) fn new_shared(width: usize, height: usize, adjacency_rules: &Rc<AdjacencyData>, adjacency_bits: &Rc<AdjacencyBits>, patterns: &Rc<P>, seed: u64) -> Self {
+    let patterns_length = patterns.len();
+    assert!(patterns_length <= I::MAX_PATTERNS, "{} patterns don't fit the grid's index type", patterns_length);
+    Self {
+      width,
+      height,
+      cells: vec![None; width * height],
+      options: vec![pattern_set(patterns_length, 0..patterns_length); width * height],
+      adjacency_rules: Rc::clone(adjacency_rules),
+      adjacency_bits: Rc::clone(adjacency_bits),
+      patterns: Rc::clone(patterns),
+      history: VecDeque::new(),
+      history_length: Some(HISTORY_LENGHT),
+      excluded: vec![],
+      cell_weights: None,
+      entropy_heap: None,
+      use_entropy_heap: true,
+      spacing_rules: vec![],
+      observe_strategy: ObserveStrategy::MinEntropy,
+      tie_break: TieBreak::Random,
+      contradiction_counts: vec![0; width * height],
+      last_contradiction: None,
+      emptied_by: HashMap::new(),
+      biomes: None,
+      banned: vec![BTreeSet::new(); width * height],
+      neighbourhood: Rc::new(Square4),
+      target_histogram: None,
+      pattern_weights: vec![1.; patterns_length],
+      count_entropy: false,
+      cell_priority: None,
+      directional_weights: None,
+      on_finalize: None,
+      on_step: None,
+      finalized: vec![false; width * height],
+      forced: vec![false; width * height],
+      minimum_counts: vec![],
+      maximum_counts: vec![],
+      collapse_strategy: CollapseStrategy::Weighted,
+      filler: None,
+      rng: SeededRng::new(seed),
+      propagation_radius: None,
+      deferred_propagation: BTreeSet::new(),
+      wrap: false,
+      redraw_mode: RedrawMode::Full,
+      dirty: RefCell::new(BTreeSet::new()),
+      #[cfg(feature = "render")]
+      render_target: RefCell::new(None),
+    }
+  }
+
+  // pattern id of a collapsed cell
+  fn cell(&self, idx: usize) -> Option<usize> {
+    self.cells[idx].map(PatternIndex::pattern)
+  }
+
+  fn place(&mut self, idx: usize, pattern: usize) {
+    self.cells[idx] = Some(I::from_pattern(pattern));
+    self.forced[idx] = false;
+  }
+
+  // topology propagation constrains across, `Square4` by default. The adjacency rules must have
+  // an entry for each of its offsets
+  pub fn with_neighbourhood(mut self, neighbourhood: Rc<dyn Neighbourhood>) -> Self {
+    self.neighbourhood = neighbourhood;
+    self
+  }
+
+  // plain offsets on the square grid, the adjacency rules must have an entry for each one
+  pub fn with_neighbours(self, neighbours: Vec<(isize, isize)>) -> Self {
+    self.with_neighbourhood(Rc::new(neighbours))
+  }
+
+  // per-pattern frequencies, observe picks the cell with the lowest Shannon entropy over them and
+  // collapse picks among its options in proportion to them, zero weight patterns are never picked
+  pub fn with_pattern_weights(mut self, pattern_weights: Vec<f32>) -> Self {
+    self.pattern_weights = pattern_weights;
+    self
+  }
+
+  // `CollapseStrategy::Weighted` by default
+  pub fn with_collapse_strategy(mut self, collapse_strategy: CollapseStrategy) -> Self {
+    self.collapse_strategy = collapse_strategy;
+    self
+  }
+
+  // collapse picks uniformly among the remaining options instead of by `pattern_weights`, as
+  // it used to, i.e. `CollapseStrategy::Random`
+  pub fn with_uniform_collapse(self, uniform_collapse: bool) -> Self {
+    self.with_collapse_strategy(if uniform_collapse { CollapseStrategy::Random } else { CollapseStrategy::Weighted })
+  }
+
+  // observe by the plain number of remaining options instead of weighted entropy
+  pub fn with_count_entropy(mut self, count_entropy: bool) -> Self {
+    self.count_entropy = count_entropy;
+    self
+  }
+
+  // how many steps can be backtracked (`HISTORY_LENGHT` by default), `None` keeps the full history
+  // so `unwind` can always walk back to a consistent state and `Some(0)` disables backtracking, a
+  // contradiction then fails right away. Once the history is full every step drops its oldest
+  // entry, so those steps can no longer be undone
+  pub fn with_history_length(mut self, history_length: Option<usize>) -> Self {
+    self.history_length = history_length;
+    self
+  }
+
+  // observe pops the lowest entropy cell from a heap kept up to date by propagation instead of
+  // scanning every cell on every step
+  pub fn with_entropy_heap(mut self, use_entropy_heap: bool) -> Self {
+    self.use_entropy_heap = use_entropy_heap;
+    self
+  }
+
+  pub fn with_redraw_mode(mut self, redraw_mode: RedrawMode) -> Self {
+    self.redraw_mode = redraw_mode;
+    self
+  }
+
+  // opposite edges of the grid are neighbours, so the output tiles seamlessly
+  pub fn with_wrap(mut self, wrap: bool) -> Self {
+    self.wrap = wrap;
+    self
+  }
+
+  // propagation stops at this Manhattan distance from the cells it started from, so only the
+  // surroundings of a change react to it. Cells at the edge that would have passed constraints on
+  // further are remembered and only propagated by `propagate_deferred`, until then cells beyond
+  // the radius may keep options that are no longer possible
+  pub fn with_propagation_radius(mut self, propagation_radius: Option<usize>) -> Self {
+    self.propagation_radius = propagation_radius;
+    self
+  }
+
+  pub fn with_observe_strategy(mut self, observe_strategy: ObserveStrategy) -> Self {
+    self.observe_strategy = observe_strategy;
+    self
+  }
+
+  // `Deterministic` reseeds the grid's RNG (collapse included), so together with `new_with_seed`
+  // (`new` reads the global RNG once for its seed) the output depends on nothing but the seed
+  pub fn with_tie_break(mut self, tie_break: TieBreak) -> Self {
+    if let TieBreak::Deterministic(seed) = tie_break {
+      self.rng = SeededRng::new(seed);
+    }
+    self.tie_break = tie_break;
+    self
+  }
+
+  // back to the state of a fresh grid (pins and bans included) reusing the existing buffers,
+  // configuration such as weights, rules and callbacks stays and the RNG carries on from where
+  // it was, so a reset grid generates a new output
+  pub fn reset(&mut self) {
+    let patterns_length = self.patterns.len();
+    self.cells.fill(None);
+    for options in self.options.iter_mut() {
+      options.insert_range(0..patterns_length);
+    }
+    for banned in self.banned.iter_mut() {
+      banned.clear();
+    }
+    self.history.clear();
+    self.excluded.clear();
+    self.entropy_heap = None;
+    self.contradiction_counts.fill(0);
+    self.last_contradiction = None;
+    self.emptied_by.clear();
+    self.finalized.fill(false);
+    self.deferred_propagation.clear();
+    self.mark_dirty(0..self.cells.len());
+    for (pattern, _) in self.maximum_counts.clone() {
+      self.apply_maximum(pattern);
+    }
+  }
+
+  fn mark_dirty(&self, tiles: impl IntoIterator<Item = usize>) {
+    if let RedrawMode::Incremental { .. } = self.redraw_mode {
+      self.dirty.borrow_mut().extend(tiles);
+    }
+  }
+
+  #[cfg(feature = "render")]
+  pub fn draw(&self) {
+    let _z = ZoneGuard::new("draw");
+    if let RedrawMode::Incremental { background } = self.redraw_mode {
+      self.draw_incremental(background);
+      return;
+    }
+    for index in 0..self.cells.len() {
+      self.draw_cell(index, GRID_OFFSET);
+    }
+  }
+
+  // the render target is created (and filled with every cell) on the first draw
+  #[cfg(feature = "render")]
+  fn draw_incremental(&self, background: Color) {
+    let (width, height) = (self.width as f32 * TILE_SIZE, self.height as f32 * TILE_SIZE);
+    let mut target = self.render_target.borrow_mut();
+    let mut dirty = self.dirty.borrow_mut();
+    let target = *target.get_or_insert_with(|| {
+      dirty.extend(0..self.cells.len());
+      render_target(width as u32, height as u32)
+    });
+    if !dirty.is_empty() {
+      // the caller's camera (e.g. a `View`) is put back for drawing the texture
+      push_camera_state();
+      set_camera(&Camera2D {
+        render_target: Some(target),
+        ..Camera2D::from_display_rect(Rect::new(0., 0., width, height))
+      });
+      for index in std::mem::take(&mut *dirty) {
+        let (x, y) = xy_from_index(index, self.width);
+        draw_rectangle(x as f32 * TILE_SIZE, y as f32 * TILE_SIZE, TILE_SIZE, TILE_SIZE, background);
+        self.draw_cell(index, 0.);
+      }
+      pop_camera_state();
+    }
+    draw_texture_ex(
+      target.texture, GRID_OFFSET, GRID_OFFSET, WHITE,
+      DrawTextureParams {
+        dest_size: Some(vec2(width, height)),
+        flip_y: true,
+        ..Default::default()
+      }
+    );
+  }
+
+  // outlines the cell of a contradiction and, when a neighbour caused it, a line towards it
+  #[cfg(feature = "render")]
+  pub fn draw_contradiction(&self, error: &WfcError) {
+    let WfcError::Contradiction { x, y, direction } = error else {
+      return;
+    };
+    let x = *x as f32 * TILE_SIZE + GRID_OFFSET;
+    let y = *y as f32 * TILE_SIZE + GRID_OFFSET;
+    draw_rectangle_lines(x, y, TILE_SIZE, TILE_SIZE, 3., RED);
+    if let Some((dx, dy)) = direction {
+      let center = vec2(x, y) + Vec2::splat(TILE_SIZE / 2.);
+      let towards = center + vec2(*dx as f32, *dy as f32) * TILE_SIZE;
+      draw_line(center.x, center.y, towards.x, towards.y, 3., RED);
+    }
+  }
+
+  // draws only the cells inside `viewport`, given in cells, for grids larger than the screen
+  #[cfg(feature = "render")]
+  pub fn draw_viewport(&self, viewport: Rect) {
+    let _z = ZoneGuard::new("draw_viewport");
+    for index in self.viewport_indices(viewport) {
+      self.draw_cell(index, GRID_OFFSET);
+    }
+  }
+
+  // indexes of the cells `viewport` (in cells) covers, clamped to the grid
+  fn viewport_indices(&self, viewport: Rect) -> Vec<usize> {
+    let clamp = |v: f32, max: usize| (v.max(0.) as usize).min(max);
+    let (min_x, max_x) = (clamp(viewport.x.floor(), self.width), clamp((viewport.x + viewport.w).ceil(), self.width));
+    let (min_y, max_y) = (clamp(viewport.y.floor(), self.height), clamp((viewport.y + viewport.h).ceil(), self.height));
+    (min_y..max_y)
+      .flat_map(|y| (min_x..max_x).map(move |x| index_from_xy(x, y, self.width)))
+      .collect()
+  }
+
+  #[cfg(feature = "render")]
+  fn draw_cell(&self, index: usize, offset: f32) {
+    let (x, y) = xy_from_index(index, self.width);
+    let x = x as f32 * TILE_SIZE + offset;
+    let y = y as f32 * TILE_SIZE + offset;
+    if let Some(p) = self.cell(index) {
+      self.patterns.draw(x, y, p);
+    } else {
+      // weighted like collapse weighs them, by frequency and by the cell's own weights
+      let options: Vec<(usize, f64)> = self.options[index].ones().map(|p| (p, self.pattern_weights[p] as f64 * self.pattern_weight(index, p))).collect();
+      self.patterns.draw_superposition(x, y, &options);
+    }
+  }
+
+  // every pattern not excluded by a global constraint, i.e. the options of a cell before any
+  // adjacency is taken into account
+  fn base_options(&self, idx: usize) -> FixedBitSet {
+    pattern_set(self.patterns.len(), (0..self.patterns.len()).filter(|p| !self.banned[idx].contains(p) && !self.is_spaced_out(idx, *p) && !self.is_at_maximum(*p)))
+  }
+
+  // bans everything outside `allowed` at `idx`, bans outlive backtracking
+  fn restrict(&mut self, idx: usize, allowed: &[usize]) {
+    self.emptied_by.remove(&idx);
+    self.banned[idx].extend((0..self.patterns.len()).filter(|p| !allowed.contains(p)));
+    self.options[idx].intersect_with(&pattern_set(self.patterns.len(), allowed.iter().copied()));
+    self.track_entropy(idx);
+  }
+
+  // the cell `dx`, `dy` away from `idx`, `None` when that's off the grid (never with `wrap`)
+  fn neighbour_index(&self, idx: usize, dx: isize, dy: isize) -> Option<usize> {
+    self.neighbourhood.neighbour(idx, (dx, dy), self.width, self.height, self.wrap)
+  }
+
+  fn contradiction_at(&self, idx: usize) -> WfcError {
+    let (x, y) = xy_from_index(idx, self.width);
+    WfcError::Contradiction { x, y, direction: self.emptied_by.get(&idx).copied() }
+  }
+
+  // the contradiction the last step ran into, if any, whether it was backtracked or not
+  pub fn last_contradiction(&self) -> Option<WfcError> {
+    self.last_contradiction.map(|(idx, direction)| {
+      let (x, y) = xy_from_index(idx, self.width);
+      WfcError::Contradiction { x, y, direction }
+    })
+  }
+
+  pub fn constrain_corners(&mut self, patterns: &[usize]) -> Result<(), WfcError> {
+    let mut corners = vec![
+      0,
+      self.width - 1,
+      index_from_xy(0, self.height - 1, self.width),
+      index_from_xy(self.width - 1, self.height - 1, self.width),
+    ];
+    corners.sort_unstable();
+    corners.dedup();
+    self.constrain_cells(corners, patterns)
+  }
+
+  // restricts every cell along `edge` to `patterns` (pins them with a single one), e.g. ground
+  // along the bottom and sky along the top. Like other constraints it outlives backtracking. For
+  // the overlapping model `ColorPattern::patterns_with_color` gives the patterns of a color
+  pub fn constrain_edge(&mut self, edge: Edge, patterns: &[usize]) -> Result<(), WfcError> {
+    let indices = match edge {
+      Edge::Top => (0..self.width).map(|x| index_from_xy(x, 0, self.width)).collect(),
+      Edge::Bottom => (0..self.width).map(|x| index_from_xy(x, self.height - 1, self.width)).collect(),
+      Edge::Left => (0..self.height).map(|y| index_from_xy(0, y, self.width)).collect(),
+      Edge::Right => (0..self.height).map(|y| index_from_xy(self.width - 1, y, self.width)).collect(),
+    };
+    self.constrain_cells(indices, patterns)
+  }
+
+  // restricts x, y to `patterns` and propagates, a pin that leaves a choice. Outlives
+  // backtracking like other constraints
+  pub fn constrain_cell(&mut self, x: usize, y: usize, patterns: &[usize]) -> Result<(), WfcError> {
+    self.constrain_cells(vec![index_from_xy(x, y, self.width)], patterns)
+  }
+
+  fn constrain_cells(&mut self, indices: Vec<usize>, patterns: &[usize]) -> Result<(), WfcError> {
+    for idx in indices.iter() {
+      self.restrict(*idx, patterns);
+      if self.options[*idx].count_ones(..) == 0 {
+        return Err(self.contradiction_at(*idx));
+      }
+    }
+    self.propagate_from(indices);
+    self.check_contradictions()
+  }
+
+  // pins `pattern` at x, y and propagates it right away, like other constraints the pin outlives
+  // backtracking
+  pub fn set_cell(&mut self, x: usize, y: usize, pattern: usize) -> Result<(), WfcError> {
+    let idx = index_from_xy(x, y, self.width);
+    if self.cells[idx].is_some() {
+      return Err(WfcError::AlreadyCollapsed { x, y });
+    }
+    if !self.options[idx].contains(pattern) {
+      return Err(self.contradiction_at(idx));
+    }
+    self.restrict(idx, &[pattern]);
+    self.propagate_from(vec![idx]);
+    self.check_contradictions()
+  }
+
+  // forbids `pattern` at `idx` and propagates, like pins the ban outlives backtracking
+  pub fn ban(&mut self, idx: usize, pattern: usize) -> Result<(), WfcError> {
+    self.ban_cells(vec![idx], pattern)
+  }
+
+  // bans `pattern` from every cell `rect` (in cells) covers
+  pub fn ban_in_rect(&mut self, rect: Rect, pattern: usize) -> Result<(), WfcError> {
+    let indices = self.viewport_indices(rect);
+    self.ban_cells(indices, pattern)
+  }
+
+  fn ban_cells(&mut self, indices: Vec<usize>, pattern: usize) -> Result<(), WfcError> {
+    for idx in indices.iter().copied() {
+      if self.cell(idx) == Some(pattern) {
+        return Err(self.contradiction_at(idx));
+      }
+      self.banned[idx].insert(pattern);
+      self.options[idx].set(pattern, false);
+      self.emptied_by.remove(&idx);
+      self.track_entropy(idx);
+    }
+    self.propagate_from(indices);
+    self.check_contradictions()
+  }
+
+  fn check_contradictions(&self) -> Result<(), WfcError> {
+    match (0..self.cells.len()).find(|idx| self.cells[*idx].is_none() && self.options[*idx].count_ones(..) == 0) {
+      Some(idx) => Err(self.contradiction_at(idx)),
+      None => Ok(()),
+    }
+  }
+
+  // keeps patterns of `group_a` and `group_b` at least `distance + 1` cells apart (Chebyshev),
+  // placing one of them bans the other group from every cell within `distance`
+  pub fn min_spacing(&mut self, group_a: &[usize], group_b: &[usize], distance: usize) {
+    self.spacing_rules.push((group_a.to_vec(), group_b.to_vec(), distance));
+  }
+
+  fn cells_within(&self, idx: usize, distance: usize) -> Vec<usize> {
+    let (x, y) = xy_from_index(idx, self.width);
+    let mut cells = vec![];
+    for ny in y.saturating_sub(distance)..=(y + distance).min(self.height - 1) {
+      for nx in x.saturating_sub(distance)..=(x + distance).min(self.width - 1) {
+        let neighbour_idx = index_from_xy(nx, ny, self.width);
+        if neighbour_idx != idx {
+          cells.push(neighbour_idx);
+        }
+      }
+    }
+    cells
+  }
+
+  fn is_spaced_out(&self, idx: usize, pattern: usize) -> bool {
+    self.spacing_rules.iter().any(|(group_a, group_b, distance)| {
+      let mut conflicting: Vec<usize> = vec![];
+      if group_a.contains(&pattern) {
+        conflicting.extend(group_b);
+      }
+      if group_b.contains(&pattern) {
+        conflicting.extend(group_a);
+      }
+      !conflicting.is_empty() && self.cells_within(idx, *distance).iter().any(|n| matches!(self.cell(*n), Some(p) if conflicting.contains(&p)))
+    })
+  }
+
+  // returns cells whose options shrank because `pattern` got placed at `idx`
+  fn enforce_spacing(&mut self, idx: usize, pattern: usize) -> Vec<usize> {
+    let mut changed = vec![];
+    for rule_idx in 0..self.spacing_rules.len() {
+      let (group_a, group_b, distance) = &self.spacing_rules[rule_idx];
+      let mut banned: Vec<usize> = vec![];
+      if group_a.contains(&pattern) {
+        banned.extend(group_b);
+      }
+      if group_b.contains(&pattern) {
+        banned.extend(group_a);
+      }
+      if banned.is_empty() {
+        continue;
+      }
+      for neighbour_idx in self.cells_within(idx, *distance) {
+        if self.cells[neighbour_idx].is_some() {
+          continue;
+        }
+        let options_before = self.options[neighbour_idx].count_ones(..);
+        for p in banned.iter() {
+          self.options[neighbour_idx].set(*p, false);
+        }
+        if self.options[neighbour_idx].count_ones(..) < options_before {
+          changed.push(neighbour_idx);
+        }
+      }
+    }
+    changed
+  }
+
+  fn pattern_weight(&self, idx: usize, pattern: usize) -> f64 {
+    match &self.cell_weights {
+      Some(weights) => weights[idx][pattern],
+      None => 1.,
+    }
+  }
+
+  // the options a cell widened back by backtracking starts from, `base_options` minus what
+  // backtracking already ruled out there under the steps still in the history
+  fn unwound_options(&self, idx: usize) -> FixedBitSet {
+    let mut options = self.base_options(idx);
+    for (cell, pattern) in self.excluded.iter().chain(self.history.iter().flat_map(|entry| entry.excluded.iter())) {
+      if *cell == idx {
+        options.set(*pattern, false);
+      }
+    }
+    options
+  }
+
+  fn unwind(&mut self) {
+    if let Some(HistoryEntry { choice: (invalid_pattern, invalid_idx), updated_tiles, options, .. }) = self.history.pop_back() {
+      self.emptied_by.clear();
+      // the undone choice is impossible for as long as the steps before it stand, it's kept with
+      // the step now on top so undoing a later step can't offer it again
+      match self.history.back_mut() {
+        Some(entry) => entry.excluded.push((invalid_idx, invalid_pattern)),
+        None => self.excluded.push((invalid_idx, invalid_pattern)),
+      }
+      // propagation only ever narrows options, so the cells touched by the undone step are
+      // widened back and re-narrowed from their surroundings
+      let mut seeds = vec![];
+      for idx in updated_tiles.iter() {
+        self.cells[*idx] = None;
+      }
+      for idx in updated_tiles.iter() {
+        self.options[*idx] = self.unwound_options(*idx);
+        seeds.push(*idx);
+        for (dx, dy) in self.neighbourhood.offsets().iter().copied() {
+          let Some(neighbour_idx) = self.neighbour_index(*idx, dx, dy) else {
+            continue;
+          };
+          if !updated_tiles.contains(&neighbour_idx) {
+            seeds.push(neighbour_idx);
+          }
+        }
+      }
+      // the stored options predate any ban placed since, so they only narrow the widened ones
+      self.options[invalid_idx].intersect_with(&options);
+      self.entropy_heap = None;
+      let settled: Vec<usize> = seeds.iter().copied().filter(|idx| self.cells[*idx].is_some()).collect();
+      let mut narrowed = self.propagate_from(seeds);
+      // what the exclusion narrows or forces holds only as long as it does, so it's undone along
+      // with the step now on top
+      if let Some(entry) = self.history.back_mut() {
+        narrowed.retain(|idx| !settled.contains(idx));
+        entry.updated_tiles.extend(narrowed);
+      }
+    }
+  }
+
+  // a contradiction with no history left to unwind (including the entries dropped once the history
+  // limit was reached) is an error, the grid stays as it is
+  pub fn step(&mut self) -> Result<StepOutcome, WfcError> {
+    let _z = ZoneGuard::new("step");
+    if self.is_finished() {
+      self.report_finalized(0..self.cells.len());
+      return Ok(StepOutcome::Finished);
+    }
+
+    let entropy_index = self.observe();
+    self.last_contradiction = None;
+    if let Some(p) = self.collapse(entropy_index) {
+      if self.history_length == Some(0) {
+        let updated_tiles = self.propagate(entropy_index);
+        self.report_step(entropy_index);
+        self.report_finalized(updated_tiles);
+        return Ok(StepOutcome::Collapsed);
+      }
+      let options_store = self.options[entropy_index].clone();
+      let updated_tiles = self.propagate(entropy_index);
+      self.report_step(entropy_index);
+      if Some(self.history.len()) == self.history_length {
+        if let Some(dropped) = self.history.pop_front() {
+          self.excluded.extend(dropped.excluded);
+          // tiles collapsed by a later step are still undone along with that step
+          let permanent: Vec<usize> = dropped.updated_tiles.into_iter()
+            .filter(|idx| !self.history.iter().any(|entry| entry.updated_tiles.contains(idx)) && !updated_tiles.contains(idx))
+            .collect();
+          self.report_finalized(permanent);
+        }
+      }
+      self.history.push_back(HistoryEntry { choice: (p, entropy_index), updated_tiles, options: options_store, excluded: vec![] });
+      if !self.minimum_counts_reachable() {
+        self.unwind();
+        return Ok(StepOutcome::Backtracked);
+      }
+      Ok(StepOutcome::Collapsed)
+    } else {
+      self.contradiction_counts[entropy_index] += 1;
+      self.last_contradiction = Some((entropy_index, self.emptied_by.get(&entropy_index).copied()));
+      // the popped contradiction cell has to be observable again
+      self.entropy_heap = None;
+      if self.history.is_empty() {
+        return Err(self.contradiction_at(entropy_index));
+      }
+      self.unwind();
+      Ok(StepOutcome::Backtracked)
+    }
+  }
+
+  // `on_finalize(x, y, pattern)` runs once for every cell as soon as it can no longer be undone,
+  // i.e. when the step that collapsed it falls out of the history or the grid is finished. Cells
+  // regenerated by `retry_cell` after being reported are not reported again
+  pub fn set_on_finalize(&mut self, on_finalize: FinalizeCallback) {
+    self.on_finalize = Some(on_finalize);
+  }
+
+  // `on_step` runs after every collapse (and the propagation following it) with the cell just
+  // collapsed, nothing is counted while no callback is set
+  pub fn set_on_step(&mut self, on_step: impl FnMut(&GridSnapshot<I>) + 'static) {
+    self.on_step = Some(Box::new(on_step));
+  }
+
+  fn report_step(&mut self, index: usize) {
+    if let Some(on_step) = &mut self.on_step {
+      on_step(&GridSnapshot {
+        collapsed: self.cells.iter().filter(|c| c.is_some()).count(),
+        total: self.cells.len(),
+        index,
+        width: self.width,
+        height: self.height,
+        cells: &self.cells,
+      });
+    }
+  }
+
+  fn report_finalized(&mut self, tiles: impl IntoIterator<Item = usize>) {
+    if let Some(on_finalize) = &mut self.on_finalize {
+      for idx in tiles {
+        if let (Some(p), false) = (self.cells[idx].map(PatternIndex::pattern), self.finalized[idx]) {
+          self.finalized[idx] = true;
+          let (x, y) = xy_from_index(idx, self.width);
+          on_finalize(x, y, p);
+        }
+      }
+    }
+  }
+
+  // clears every cell within `radius` of `idx` and re-derives their options from the
+  // surroundings, so only that neighbourhood is generated again
+  pub fn retry_cell(&mut self, idx: usize, radius: usize) {
+    let mut region = self.cells_within(idx, radius);
+    region.push(idx);
+    for i in region.iter() {
+      self.cells[*i] = None;
+      self.emptied_by.remove(i);
+    }
+    let mut seeds = vec![];
+    for i in region.iter() {
+      self.options[*i] = self.base_options(*i);
+      seeds.push(*i);
+      for (dx, dy) in self.neighbourhood.offsets().to_vec() {
+        if let Some(n) = self.neighbour_index(*i, dx, dy) {
+          if !region.contains(&n) && !seeds.contains(&n) {
+            seeds.push(n);
+          }
+        }
+      }
+    }
+    self.history.clear();
+    self.excluded.clear();
+    self.entropy_heap = None;
+    self.propagate_from(seeds);
+  }
+
+  // steps until finished or `max_steps` ran out, a cell that keeps being the site of
+  // contradictions gets its neighbourhood regenerated instead of backtracking further
+  pub fn generate_with_local_retry(&mut self, hotspot_threshold: usize, radius: usize, max_steps: usize) -> bool {
+    for _ in 0..max_steps {
+      if self.is_finished() {
+        return true;
+      }
+      let stuck = self.step().is_err();
+      if let Some((idx, _)) = self.last_contradiction {
+        if stuck || self.contradiction_counts[idx] >= hotspot_threshold {
+          self.contradiction_counts[idx] = 0;
+          self.retry_cell(idx, radius);
+        }
+      }
+    }
+    self.is_finished()
+  }
+
+  fn observe(&mut self) -> usize {
+    let _z = ZoneGuard::new("observe");
+    if self.observe_strategy == ObserveStrategy::MaxImpact {
+      return self.observe_max_impact();
+    }
+    if let Some((priorities, true)) = &self.cell_priority {
+      return self.observe_by_priority(priorities);
+    }
+    if self.use_entropy_heap {
+      return self.pop_lowest_entropy();
+    }
+    let (_, lowest_entropy) = self.scan_lowest_entropy();
+    if let Some((priorities, _)) = &self.cell_priority {
+      let top_priority = lowest_entropy.iter().map(|i| priorities[*i]).fold(f32::MIN, f32::max);
+      let prioritized: Vec<usize> = lowest_entropy.iter().copied().filter(|i| priorities[*i] == top_priority).collect();
+      return self.break_tie(&prioritized);
+    }
+
+    self.break_tie(&lowest_entropy)
+  }
+
+  fn break_tie(&self, candidates: &[usize]) -> usize {
+    match self.tie_break {
+      TieBreak::FirstIndex => *candidates.iter().min().unwrap(),
+      TieBreak::Random | TieBreak::Deterministic(_) => *self.rng.choose(candidates).unwrap(),
+    }
+  }
+
+  // highest priority first and lowest entropy among those, contradictions still come first
+  fn observe_by_priority(&self, priorities: &[f32]) -> usize {
+    let mut best: Vec<usize> = vec![];
+    let mut best_key = None;
+    for (i, priority) in priorities.iter().enumerate() {
+      if self.cells[i].is_some() {
+        continue;
+      }
+      let key = (self.options[i].count_ones(..) > 0, -priority, self.entropy(i));
+      match best_key.and_then(|best_key| key.partial_cmp(&best_key)) {
+        Some(Ordering::Equal) => best.push(i),
+        Some(Ordering::Greater) => {},
+        _ => {
+          best_key = Some(key);
+          best = vec![i];
+        },
+      }
+    }
+    self.break_tie(&best)
+  }
+
+  // Shannon entropy of the remaining options under `pattern_weights`, or just their count
+  fn entropy(&self, idx: usize) -> f64 {
+    let options = &self.options[idx];
+    if self.count_entropy {
+      return options.count_ones(..) as f64;
+    }
+    if options.count_ones(..) == 0 {
+      // contradictions sort before any cell that still has a choice
+      return -1.;
+    }
+    let weights: Vec<f64> = options.ones().map(|p| self.pattern_weights[p] as f64).filter(|w| *w > 0.).collect();
+    let total: f64 = weights.iter().sum();
+    if total <= 0. {
+      return 0.;
+    }
+    total.ln() - weights.iter().map(|w| w * w.ln()).sum::<f64>() / total
+  }
+
+  fn observe_max_impact(&self) -> usize {
+    let mut highest_impact: Vec<usize> = vec![];
+    let mut highest_impact_value = f64::MIN;
+    for i in 0..self.options.len() {
+      if self.cells[i].is_some() {
+        continue;
+      }
+      if self.options[i].count_ones(..) == 0 {
+        return i;
+      }
+      let impact_value = self.estimate_impact(i);
+      if impact_value > highest_impact_value {
+        highest_impact_value = impact_value;
+        highest_impact.clear();
+        highest_impact.push(i);
+      } else if impact_value == highest_impact_value {
+        highest_impact.push(i);
+      }
+    }
+
+    self.break_tie(&highest_impact)
+  }
+
+  // options removed by collapsing `idx`: its own plus, averaged over its options, those its
+  // direct neighbours lose according to the adjacency rules
+  fn estimate_impact(&self, idx: usize) -> f64 {
+    let options = &self.options[idx];
+    let option_count = options.count_ones(..);
+    let mut impact = (option_count - 1) as f64;
+    for (dx, dy) in self.neighbourhood.offsets().iter().copied() {
+      let Some(neighbour_idx) = self.neighbour_index(idx, dx, dy) else {
+        continue;
+      };
+      if self.cells[neighbour_idx].is_some() {
+        continue;
+      }
+      let neighbour_options = &self.options[neighbour_idx];
+      let kept: usize = options.ones()
+        .map(|p| self.adjacency_bits[p][&(dx, dy)].intersection(neighbour_options).count())
+        .sum();
+      impact += neighbour_options.count_ones(..) as f64 - kept as f64 / option_count as f64;
+    }
+    impact
+  }
+
+  // the heap holds an entry with the current entropy of every uncollapsed cell, entries left behind
+  // by earlier (higher) entropies are stale and skipped when popped. Propagation only ever lowers
+  // entropy, everything that widens options drops the heap so it gets rebuilt
+  fn pop_lowest_entropy(&mut self) -> usize {
+    let mut heap = match self.entropy_heap.take() {
+      Some(heap) => heap,
+      None => (0..self.cells.len()).filter(|i| self.cells[*i].is_none()).map(|i| self.entropy_entry(i)).collect(),
+    };
+    // the heap orders entropies exactly, every current entry within ENTROPY_EPSILON of the lowest
+    // is taken off so they tie on priority and tiebreak alone, like in `scan_lowest_entropy`
+    let mut ties: Vec<EntropyEntry> = vec![];
+    while let Some(entry) = heap.peek() {
+      if ties.first().is_some_and(|lowest| entry.entropy - lowest.entropy >= ENTROPY_EPSILON) {
+        break;
+      }
+      let entry = heap.pop().unwrap();
+      if self.cells[entry.idx].is_none() && (self.entropy(entry.idx) - entry.entropy).abs() < ENTROPY_EPSILON {
+        ties.push(entry);
+      }
+    }
+    let best = ties.iter().enumerate()
+      .max_by(|(_, a), (_, b)| a.priority.total_cmp(&b.priority).then(a.tiebreak.cmp(&b.tiebreak)))
+      .map(|(position, _)| position)
+      .expect("observe is only called while some cell is uncollapsed");
+    let idx = ties.swap_remove(best).idx;
+    heap.extend(ties);
+    self.entropy_heap = Some(heap);
+    idx
+  }
+
+  fn entropy_entry(&self, idx: usize) -> EntropyEntry {
+    EntropyEntry {
+      entropy: self.entropy(idx),
+      priority: self.cell_priority.as_ref().map_or(0., |(priorities, _)| priorities[idx]),
+      tiebreak: match self.tie_break {
+        TieBreak::FirstIndex => u32::MAX - idx as u32,
+        TieBreak::Random | TieBreak::Deterministic(_) => self.rng.next_u64() as u32,
+      },
+      idx,
+    }
+  }
+
+  fn track_entropy(&mut self, idx: usize) {
+    if self.entropy_heap.is_none() || self.cells[idx].is_some() {
+      return;
+    }
+    let entry = self.entropy_entry(idx);
+    if let Some(heap) = &mut self.entropy_heap {
+      heap.push(entry);
+    }
+  }
+
+  fn scan_lowest_entropy(&self) -> (f64, Vec<usize>) {
+    let mut lowest_entropy: Vec<usize> = vec![];
+    let mut lowest_entropy_value = f64::MAX;
+    for i in 0..self.options.len() {
+      if self.cells[i].is_some() {
+        continue;
+      }
+      let entropy_value = self.entropy(i);
+      if entropy_value < lowest_entropy_value - ENTROPY_EPSILON {
+        lowest_entropy_value = entropy_value;
+        lowest_entropy.clear();
+        lowest_entropy.push(i);
+      } else if (entropy_value - lowest_entropy_value).abs() < ENTROPY_EPSILON {
+        lowest_entropy.push(i);
+      }
+    }
+
+    (lowest_entropy_value, lowest_entropy)
+  }
+
+  pub fn is_finished(&self) -> bool {
+    self.cells.iter().all(|v| v.is_some())
+  }
+
+  pub fn collapsed_count(&self) -> usize {
+    self.cells.iter().filter(|c| c.is_some()).count()
+  }
+
+  // share of collapsed cells, from 0 to 1, e.g. for a progress bar
+  pub fn progress(&self) -> f32 {
+    self.collapsed_count() as f32 / self.cells.len() as f32
+  }
+
+  // steps until every cell is collapsed or a contradiction can't be backtracked, nothing gets drawn
+  // so it works outside of a macroquad window too
+  pub fn run(&mut self) -> Result<(), WfcError> {
+    loop {
+      if self.step()? == StepOutcome::Finished {
+        return Ok(());
+      }
+    }
+  }
+
+  // `run`, but a contradiction backtracking can't resolve starts the whole generation over from
+  // the state the grid was in when called (so earlier pins and bans stay) with a seed derived from
+  // the current one, at most `max_restarts` times. Gives the number of restarts it took, for
+  // tight rulesets this is often quicker than deep backtracking. A restart
+  // undoes every cell, so while one is left `on_finalize` is held back and runs for every cell
+  // once the grid is finished, only the last attempt reports cells as they settle
+  pub fn generate(&mut self, max_restarts: usize) -> Result<usize, WfcError> {
+    let start = (self.cells.clone(), self.options.clone(), self.banned.clone(), self.forced.clone(), self.deferred_propagation.clone());
+    let mut held_back = self.on_finalize.take();
+    let mut restarts = 0;
+    loop {
+      if restarts == max_restarts {
+        self.on_finalize = self.on_finalize.take().or(held_back.take());
+      }
+      match self.run() {
+        Ok(()) => {
+          if let Some(on_finalize) = held_back.take() {
+            self.on_finalize = Some(on_finalize);
+            self.report_finalized(0..self.cells.len());
+          }
+          return Ok(restarts);
+        },
+        Err(error) if restarts == max_restarts => return Err(error),
+        Err(_) => {},
+      }
+      restarts += 1;
+      (self.cells, self.options, self.banned, self.forced, self.deferred_propagation) = start.clone();
+      self.history.clear();
+      self.excluded.clear();
+      self.entropy_heap = None;
+      self.contradiction_counts.fill(0);
+      self.last_contradiction = None;
+      self.emptied_by.clear();
+      self.mark_dirty(0..self.cells.len());
+      self.rng = SeededRng::new(self.rng.next_u64());
+    }
+  }
+
+  // yields the outcome of every step until the grid is finished (the last item) or fails, the
+  // grid can be looked at in between through `Steps::grid`
+  pub fn steps(&mut self) -> Steps<'_, P, I> {
+    Steps { grid: self, done: false }
+  }
+
+  // up to `n` steps, stopping early once the grid is finished or a step backtracks so the
+  // contradiction can be looked at, gives the last step's outcome
+  pub fn step_n(&mut self, n: usize) -> Result<StepOutcome, WfcError> {
+    let mut outcome = StepOutcome::Collapsed;
+    for _ in 0..n {
+      outcome = self.step()?;
+      if outcome != StepOutcome::Collapsed {
+        break;
+      }
+    }
+    Ok(outcome)
+  }
+
+  // `step_n` bounded by wall-clock time instead, steps (at least once) until `budget` is used up,
+  // e.g. a frame's worth of generation in a render loop
+  pub fn step_for(&mut self, budget: Duration) -> Result<StepOutcome, WfcError> {
+    let start = Instant::now();
+    loop {
+      let outcome = self.step()?;
+      if outcome != StepOutcome::Collapsed || start.elapsed() >= budget {
+        return Ok(outcome);
+      }
+    }
+  }
+
+  // steps until every cell is collapsed, gives up after `max_steps`
+  pub fn run_to_completion(&mut self, max_steps: usize) -> bool {
+    for _ in 0..max_steps {
+      match self.step() {
+        Ok(StepOutcome::Finished) => return true,
+        Ok(_) => {},
+        Err(_) => return false,
+      }
+    }
+    self.is_finished()
+  }
+
+  // every complete assignment the rules allow (pattern indexes row by row), up to `limit` of them,
+  // found by a depth-first search driven by propagation. Exhaustive, so only practical for small
+  // grids and tilesets. The grid is left as it was
+  pub fn enumerate_solutions(&mut self, limit: usize) -> Vec<Vec<usize>> {
+    // the search collapses and propagates like generating does, RNG included, all of which is
+    // put back afterwards so generation carries on as if it never ran
+    let saved = (self.cells.clone(), self.options.clone(), self.forced.clone(), self.emptied_by.clone(), self.deferred_propagation.clone());
+    let (dirty, rng) = (self.dirty.borrow().clone(), self.rng.state());
+    let entropy_heap = self.entropy_heap.take();
+    let mut solutions = vec![];
+    if self.check_contradictions().is_ok() {
+      self.enumerate_from(limit, &mut solutions);
+    }
+    (self.cells, self.options, self.forced, self.emptied_by, self.deferred_propagation) = saved;
+    self.dirty.replace(dirty);
+    self.rng = SeededRng::from_state(rng);
+    self.entropy_heap = entropy_heap;
+    solutions
+  }
+
+  fn enumerate_from(&mut self, limit: usize, solutions: &mut Vec<Vec<usize>>) {
+    let open = (0..self.cells.len()).filter(|idx| self.cells[*idx].is_none()).min_by_key(|idx| self.options[*idx].count_ones(..));
+    let Some(idx) = open else {
+      if self.quick_consistency_check() {
+        solutions.push(self.cells.iter().map(|c| c.unwrap().pattern()).collect());
+      }
+      return;
+    };
+    for pattern in self.options[idx].ones().collect::<Vec<usize>>() {
+      if solutions.len() >= limit {
+        return;
+      }
+      let saved = (self.cells.clone(), self.options.clone());
+      // placed directly, `collapse` wouldn't pick a pattern weighing 0 and the cell would stay open
+      self.options[idx] = pattern_set(self.patterns.len(), [pattern]);
+      self.place(idx, pattern);
+      self.propagate_from(vec![idx]);
+      if self.check_contradictions().is_ok() {
+        self.enumerate_from(limit, solutions);
+      }
+      (self.cells, self.options) = saved;
+    }
+  }
+
+  pub fn width(&self) -> usize {
+    self.width
+  }
+
+  pub fn height(&self) -> usize {
+    self.height
+  }
+
+  // collapsed pattern at x, y, `None` while undecided or off the grid
+  pub fn get(&self, x: usize, y: usize) -> Option<usize> {
+    if x >= self.width || y >= self.height {
+      return None;
+    }
+    self.cell(index_from_xy(x, y, self.width))
+  }
+
+  // pattern of every cell row by row, `None` until the grid is finished
+  pub fn to_indices(&self) -> Option<Vec<usize>> {
+    self.cells.iter().map(|c| c.map(PatternIndex::pattern)).collect()
+  }
+
+  pub fn history_len(&self) -> usize {
+    self.history.len()
+  }
+
+  fn collapse(&mut self, idx: usize) -> Option<usize> {
+    let _z = ZoneGuard::new("collapse");
+    let required = self.minimum_counts.iter()
+      .filter(|(pattern, _)| self.options[idx].contains(*pattern))
+      .find(|(pattern, count)| {
+        let (placed, open) = self.pattern_capacity(*pattern);
+        placed < *count && self.rng.gen_range(0., 1.) < (*count - placed) as f64 / open as f64
+      });
+    if let Some(&(pattern, _)) = required {
+      self.place(idx, pattern);
+      return Some(pattern);
+    }
+    if self.collapse_strategy == CollapseStrategy::LowestId {
+      let lowest = self.options[idx].ones().next();
+      return lowest.inspect(|p| self.place(idx, *p));
+    }
+    let uniform = self.collapse_strategy == CollapseStrategy::Random;
+    let options: Vec<usize> = self.options[idx].ones().collect();
+    let choice = if !uniform || self.cell_weights.is_some() || self.biomes.is_some() || self.target_histogram.is_some() || self.filler.is_some() || self.directional_weights.is_some() {
+      let histogram = if self.target_histogram.is_some() { self.pattern_histogram() } else { vec![] };
+      weighted_choice(&self.rng, &options, |p| {
+        let frequency = if uniform { 1. } else { self.pattern_weights[p] as f64 };
+        let filler = match self.filler {
+          Some((filler, bias)) if filler == p => bias,
+          _ => 1.,
+        };
+        frequency * filler * self.pattern_weight(idx, p) * self.transition_weight(idx, p) * self.histogram_weight(&histogram, p) * self.directional_weight(idx, p)
+      })
+    } else {
+      self.rng.choose(&options).copied()
+    };
+    choice.inspect(|p| self.place(idx, *p))
+  }
+
+  // multiplies the collapse weight of `pattern` by `bias`, with a large bias it fills every cell
+  // that still allows it and adjacency alone decides the rest
+  pub fn set_filler(&mut self, pattern: usize, bias: f64) {
+    self.filler = Some((pattern, bias));
+  }
+
+  // keeps at least `count` cells at `pattern`, collapse picks it with a probability growing as the
+  // cells that can still hold it run out (forced once there are just enough of them) and a step
+  // leaving too few of them is backtracked
+  pub fn require_at_least(&mut self, pattern: usize, count: usize) {
+    self.minimum_counts.push((pattern, count));
+  }
+
+  // keeps at most `count` cells at `pattern`, as soon as that many hold it it's removed from every
+  // undecided cell. Counts come from the cells themselves, so a step undone by backtracking makes
+  // the pattern available again. With `require_at_least` as well it's an exact count
+  pub fn require_at_most(&mut self, pattern: usize, count: usize) {
+    self.maximum_counts.push((pattern, count));
+    self.apply_maximum(pattern);
+  }
+
+  // a maximum already reached (e.g. a maximum of 0) narrows the grid right away
+  fn apply_maximum(&mut self, pattern: usize) {
+    let limited = self.enforce_maximum(pattern);
+    for idx in limited.iter() {
+      self.track_entropy(*idx);
+    }
+    if !limited.is_empty() {
+      self.propagate_from(limited);
+    }
+  }
+
+  fn placed_count(&self, pattern: usize) -> usize {
+    self.cells.iter().filter(|c| c.is_some_and(|p| p.pattern() == pattern)).count()
+  }
+
+  fn is_at_maximum(&self, pattern: usize) -> bool {
+    self.maximum_counts.iter().any(|(p, count)| *p == pattern && self.placed_count(pattern) >= *count)
+  }
+
+  // returns undecided cells that lost `pattern` because it reached its maximum
+  fn enforce_maximum(&mut self, pattern: usize) -> Vec<usize> {
+    if !self.is_at_maximum(pattern) {
+      return vec![];
+    }
+    let mut changed = vec![];
+    for idx in 0..self.cells.len() {
+      if self.cells[idx].is_none() && self.options[idx].contains(pattern) {
+        self.options[idx].set(pattern, false);
+        changed.push(idx);
+      }
+    }
+    changed
+  }
+
+  // cells holding `pattern` and undecided cells that still could
+  fn pattern_capacity(&self, pattern: usize) -> (usize, usize) {
+    let placed = self.placed_count(pattern);
+    let open = (0..self.cells.len()).filter(|idx| self.cells[*idx].is_none() && self.options[*idx].contains(pattern)).count();
+    (placed, open)
+  }
+
+  fn minimum_counts_reachable(&self) -> bool {
+    self.minimum_counts.iter().all(|(pattern, count)| {
+      let (placed, open) = self.pattern_capacity(*pattern);
+      placed + open >= *count
+    })
+  }
+
+  // steers collapse towards the given (pattern, proportion) distribution, proportions are
+  // normalized and patterns missing from `targets` are only picked when nothing else fits
+  pub fn set_target_histogram(&mut self, targets: &[(usize, f64)]) {
+    let total: f64 = targets.iter().map(|(_, share)| share).sum();
+    let mut histogram = vec![0.; self.patterns.len()];
+    for (pattern, share) in targets {
+      histogram[*pattern] = share / total;
+    }
+    self.target_histogram = Some(histogram);
+  }
+
+  // how many collapsed cells hold each pattern
+  pub fn pattern_histogram(&self) -> Vec<usize> {
+    let mut histogram = vec![0; self.patterns.len()];
+    for p in self.cells.iter().flatten() {
+      histogram[p.pattern()] += 1;
+    }
+    histogram
+  }
+
+  // the running deficit of a pattern, i.e. how many more cells it should hold after this collapse
+  // to match its target proportion. Patterns without one keep a tiny weight so a cell adjacency
+  // leaves only those for still collapses
+  fn histogram_weight(&self, histogram: &[usize], pattern: usize) -> f64 {
+    match &self.target_histogram {
+      Some(targets) => {
+        let collapsed: usize = histogram.iter().sum();
+        (targets[pattern] * (collapsed + 1) as f64 - histogram[pattern] as f64).max(1e-6)
+      },
+      None => 1.,
+    }
+  }
+
+  // cells with a higher priority are observed first among equal-entropy cells, with
+  // `override_entropy` priority wins over entropy as well
+  pub fn set_cell_priority(&mut self, priorities: Vec<f32>, override_entropy: bool) {
+    self.cell_priority = Some((priorities, override_entropy));
+    self.entropy_heap = None;
+  }
+
+  // patterns that connect well to two different biome groups get their weight multiplied by up to
+  // `1 + transition_boost` in cells at the seam between those groups
+  pub fn set_biome_groups(&mut self, groups: Vec<Vec<usize>>, transition_boost: f64) {
+    self.biomes = Some((groups, transition_boost));
+  }
+
+  // multiplies the collapse weight of `pattern` by `weight` in cells whose neighbour at
+  // `direction` holds `neighbour`, so output drifts along it, e.g. a path continuing south is
+  // preferred below a path with `(path, (0, -1), path)`. Off (and costing nothing) until the
+  // first one is set
+  pub fn set_directional_weight(&mut self, neighbour: usize, direction: (isize, isize), pattern: usize, weight: f64) {
+    self.directional_weights.get_or_insert_with(HashMap::new).insert((neighbour, direction, pattern), weight);
+  }
+
+  fn directional_weight(&self, idx: usize, pattern: usize) -> f64 {
+    let Some(weights) = &self.directional_weights else {
+      return 1.;
+    };
+    self.neighbourhood.offsets().iter()
+      .filter_map(|(dx, dy)| {
+        let neighbour = self.neighbour_index(idx, *dx, *dy).and_then(|neighbour_idx| self.cell(neighbour_idx))?;
+        weights.get(&(neighbour, (*dx, *dy), pattern)).copied()
+      })
+      .product()
+  }
+
+  // a cell sits on a seam when its collapsed neighbours belong to a group and either other
+  // neighbours or its own remaining options belong to a different one
+  fn transition_weight(&self, idx: usize, pattern: usize) -> f64 {
+    let (groups, transition_boost) = match &self.biomes {
+      Some(biomes) => biomes,
+      None => return 1.,
+    };
+    let group_of = |p: usize| groups.iter().position(|g| g.contains(&p));
+
+    let mut neighbour_groups: BTreeSet<usize> = BTreeSet::new();
+    for (dx, dy) in self.neighbourhood.offsets().iter().copied() {
+      let Some(neighbour_idx) = self.neighbour_index(idx, dx, dy) else {
+        continue;
+      };
+      if let Some(group) = self.cell(neighbour_idx).and_then(group_of) {
+        neighbour_groups.insert(group);
+      }
+    }
+    let mut seam_groups = neighbour_groups.clone();
+    seam_groups.extend(self.options[idx].ones().filter_map(group_of));
+
+    // share of a group's patterns this pattern may border in any direction
+    let compatible: BTreeSet<usize> = self.adjacency_rules[pattern].values().flatten().copied().collect();
+    let reach = |group: usize| groups[group].iter().filter(|p| compatible.contains(p)).count() as f64 / groups[group].len().max(1) as f64;
+
+    let mut bridge: f64 = 0.;
+    for a in neighbour_groups.iter() {
+      for b in seam_groups.iter().filter(|b| *b != a) {
+        bridge = bridge.max(reach(*a) * reach(*b));
+      }
+    }
+    1. + transition_boost * bridge
+  }
+
+  // samples `noise` at every cell (in normalized 0..1 coordinates) and turns the value into
+  // per-pattern collapse weights, patterns missing from the mapping keep a weight of 1
+  pub fn apply_noise_weights(&mut self, noise: impl Fn(f32, f32) -> f32, mapping: impl Fn(f32) -> Vec<(usize, f64)>) {
+    let mut weights = vec![vec![1.; self.patterns.len()]; self.cells.len()];
+    for (idx, cell_weights) in weights.iter_mut().enumerate() {
+      let (x, y) = xy_from_index(idx, self.width);
+      let value = noise((x as f32 + 0.5) / self.width as f32, (y as f32 + 0.5) / self.height as f32);
+      for (pattern, weight) in mapping(value) {
+        cell_weights[pattern] = weight;
+      }
+    }
+    self.cell_weights = Some(weights);
+  }
+
+  fn propagate(&mut self, idx: usize) -> BTreeSet<usize> {
+    self.propagate_from(vec![idx])
+  }
+
+  // passes on everything propagation held back at the radius, without any radius
+  pub fn propagate_deferred(&mut self) -> Result<(), WfcError> {
+    let seeds: Vec<usize> = std::mem::take(&mut self.deferred_propagation).into_iter().collect();
+    let radius = self.propagation_radius.take();
+    self.propagate_from(seeds);
+    self.propagation_radius = radius;
+    self.check_contradictions()
+  }
+
+  // everything here iterates in a fixed order (no hash-ordered collections), so the same seed
+  // and ruleset always consume the RNG in the same sequence and yield the same output
+  fn propagate_from(&mut self, seeds: Vec<usize>) -> BTreeSet<usize> {
+    let _z = ZoneGuard::new("propagate");
+    let origins: Vec<usize> = match self.propagation_radius {
+      Some(_) => seeds.clone(),
+      None => vec![],
+    };
+    let (radius, width, height, wrap) = (self.propagation_radius, self.width, self.height, self.wrap);
+    let within_radius = |idx: usize| {
+      radius.is_none_or(|radius| origins.iter().any(|origin| manhattan_distance(idx, *origin, width, height, wrap) <= radius))
+    };
+    // FIFO queue with a membership bitset, so enqueueing is O(1); seeds are reversed to keep the
+    // order they were processed in before. Options only ever shrink here, so arc consistency
+    // settles on the same fixpoint whatever order the queue is drained in
+    let mut queued = FixedBitSet::with_capacity(self.cells.len());
+    let mut queue: VecDeque<usize> = VecDeque::with_capacity(seeds.len());
+    for idx in seeds.into_iter().rev() {
+      queued.insert(idx);
+      queue.push_back(idx);
+    }
+    let mut visited_tiles: BTreeSet<usize> = BTreeSet::new();
+
+    // a tile is re-processed every time its options shrink, otherwise constraints reaching it
+    // from a second direction would never be passed on
+    while let Some(idx) = queue.pop_front() {
+      queued.set(idx, false);
+      visited_tiles.insert(idx);
+      if self.cells[idx].is_none() && self.options[idx].count_ones(..) == 1 && self.collapse(idx).is_some() {
+        self.forced[idx] = true;
+      }
+      // an emptied cell is already a contradiction, constraining further from it would only
+      // empty its neighbours as well and hide where it started
+      if self.cells[idx].is_none() && self.options[idx].count_ones(..) == 0 {
+        continue;
+      }
+      if let Some(pattern) = self.cell(idx) {
+        let mut limited = self.enforce_spacing(idx, pattern);
+        limited.extend(self.enforce_maximum(pattern));
+        for neighbour_idx in limited {
+          self.track_entropy(neighbour_idx);
+          if !queued.put(neighbour_idx) {
+            queue.push_back(neighbour_idx);
+          }
+        }
+      }
+      for n_idx in 0..self.neighbourhood.offsets().len() {
+        let (dx, dy) = self.neighbourhood.offsets()[n_idx];
+        let Some(neighbour_idx) = self.neighbour_index(idx, dx, dy) else {
+          continue;
+        };
+        if self.cells[neighbour_idx].is_some() {
+          continue;
+        }
+        if !within_radius(neighbour_idx) {
+          self.deferred_propagation.insert(idx);
+          continue;
+        }
+        let overlaps: FixedBitSet = if let Some(pattern) = self.cell(idx) {
+          self.adjacency_bits[pattern][&(dx, dy)].clone()
+        } else {
+          let mut union = FixedBitSet::with_capacity(self.patterns.len());
+          for opt in self.options[idx].ones() {
+            union.union_with(&self.adjacency_bits[opt][&(dx, dy)]);
+          }
+          union
+        };
+
+        let options_before = self.options[neighbour_idx].count_ones(..);
+        self.options[neighbour_idx].intersect_with(&overlaps);
+        let options_now = self.options[neighbour_idx].count_ones(..);
+
+        if options_now == 0 {
+          self.emptied_by.entry(neighbour_idx).or_insert((-dx, -dy));
+        }
+        if options_now < options_before {
+          self.track_entropy(neighbour_idx);
+          if !queued.put(neighbour_idx) {
+            queue.push_back(neighbour_idx);
+          }
+        }
+      }
+    }
+
+    self.mark_dirty(visited_tiles.iter().copied());
+    visited_tiles
+  }
+
+  // runs arc consistency from every constrained cell on a scratch copy of the options,
+  // without collapsing anything; `false` means the current constraints can't be satisfied
+  pub fn quick_consistency_check(&self) -> bool {
+    let mut options: Vec<FixedBitSet> = self.cells.iter().zip(self.options.iter())
+      .map(|(cell, opts)| match cell {
+        Some(p) => pattern_set(self.patterns.len(), [p.pattern()]),
+        None => opts.clone(),
+      })
+      .collect();
+    let mut queue: VecDeque<usize> = (0..options.len()).filter(|idx| options[*idx].count_ones(..) < self.patterns.len()).collect();
+    let mut queued = FixedBitSet::with_capacity(options.len());
+    queued.extend(queue.iter().copied());
+
+    while let Some(idx) = queue.pop_front() {
+      queued.set(idx, false);
+      if options[idx].count_ones(..) == 0 {
+        return false;
+      }
+      for (dx, dy) in self.neighbourhood.offsets().iter().copied() {
+        let Some(neighbour_idx) = self.neighbour_index(idx, dx, dy) else {
+          continue;
+        };
+        let mut overlaps = FixedBitSet::with_capacity(self.patterns.len());
+        for opt in options[idx].ones() {
+          overlaps.union_with(&self.adjacency_bits[opt][&(dx, dy)]);
+        }
+        let options_before = options[neighbour_idx].count_ones(..);
+        options[neighbour_idx].intersect_with(&overlaps);
+        let options_now = options[neighbour_idx].count_ones(..);
+        if options_now == 0 {
+          return false;
+        }
+        if options_now < options_before && !queued.put(neighbour_idx) {
+          queue.push_back(neighbour_idx);
+        }
+      }
+    }
+
+    true
+  }
+
+  pub fn finalize(self) -> Result<SolvedGrid<P>, WfcError> {
+    if !self.is_finished() {
+      return Err(WfcError::NotFinished);
+    }
+    Ok(SolvedGrid {
+      width: self.width,
+      height: self.height,
+      cells: self.cells.iter().map(|c| c.unwrap().pattern()).collect(),
+      patterns: self.patterns,
+    })
+  }
+
+  // writes the finished grid as a PNG, every cell a TILE_SIZE block rasterized from its pattern,
+  // nothing is drawn so it works without a window
+  pub fn export_png(&self, path: &str) -> Result<(), WfcError> {
+    let cells = self.to_indices().ok_or(WfcError::NotFinished)?;
+    let solved = SolvedGrid { width: self.width, height: self.height, cells, patterns: Rc::clone(&self.patterns) };
+    solved.export_png(path)
+  }
+
+  // the pattern constraints alone force at x, y: the only option left of an undecided cell, or
+  // the pattern of a cell propagation collapsed because nothing else was left. `None` for cells
+  // that still have a choice and for ones observe chose
+  pub fn forced_pattern_at(&self, x: usize, y: usize) -> Option<usize> {
+    let idx = index_from_xy(x, y, self.width);
+    match self.cell(idx) {
+      Some(p) if self.forced[idx] => Some(p),
+      Some(_) => None,
+      None if self.options[idx].count_ones(..) == 1 => self.options[idx].ones().next(),
+      None => None,
+    }
+  }
+
+  // collapsed pattern of each neighbour in `N_INDEXES` order, `None` if undecided or off-grid
+  pub fn neighbors_collapsed(&self, index: usize) -> [Option<usize>; 4] {
+    let mut neighbours = [None; 4];
+    for (n_idx, (dx, dy)) in N_INDEXES.iter().enumerate() {
+      let Some(neighbour_idx) = self.neighbour_index(index, *dx, *dy) else {
+        continue;
+      };
+      neighbours[n_idx] = self.cell(neighbour_idx);
+    }
+    neighbours
+  }
+
+  // Tiled layer CSV, `gid_map[pattern]` is the GID written for a pattern, undecided cells are
+  // written as 0 (no tile)
+  pub fn export_tiled_csv(&self, gid_map: &[u32]) -> String {
+    let rows: Vec<String> = self.cells.chunks(self.width)
+      .map(|row| row.iter().map(|cell| cell.map_or(0, |p| gid_map[p.pattern()]).to_string()).collect::<Vec<String>>().join(","))
+      .collect();
+    rows.join(",\n") + "\n"
+  }
+
+  // one line per row, `charset[pattern]` for collapsed cells, `?` for undecided ones and `#` for
+  // patterns past the end of `charset`
+  pub fn to_ascii(&self, charset: &[char]) -> String {
+    let mut ascii = String::with_capacity((self.width + 1) * self.height);
+    for (index, cell) in self.cells.iter().enumerate() {
+      let (x, _) = xy_from_index(index, self.width);
+      ascii.push(match cell {
+        Some(p) => charset.get(p.pattern()).copied().unwrap_or('#'),
+        None => '?',
+      });
+      if x == self.width - 1 {
+        ascii.push('\n');
+      }
+    }
+    ascii
+  }
+
+  pub fn pattern_positions(&self) -> HashMap<usize, Vec<(usize, usize)>> {
+    let mut positions: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+    for (idx, cell) in self.cells.iter().enumerate() {
+      if let Some(p) = cell {
+        positions.entry(p.pattern()).or_default().push(xy_from_index(idx, self.width));
+      }
+    }
+    positions
+  }
+
+  pub fn remove_small_regions<F: Fn(&[usize]) -> Option<usize>>(&mut self, min_size: usize, replacement_selector: F) -> usize {
+    let mut replaced = 0;
+    let mut visited = vec![false; self.cells.len()];
+
+    for start in 0..self.cells.len() {
+      let pattern = match self.cell(start) {
+        Some(p) if !visited[start] => p,
+        _ => continue,
+      };
+      let mut region = vec![];
+      let mut border_patterns: BTreeSet<usize> = BTreeSet::new();
+      let mut stack = vec![start];
+      visited[start] = true;
+      while let Some(idx) = stack.pop() {
+        region.push(idx);
+        for (dx, dy) in N_INDEXES {
+          let Some(neighbour_idx) = self.neighbour_index(idx, dx, dy) else {
+            continue;
+          };
+          match self.cell(neighbour_idx) {
+            Some(p) if p == pattern && !visited[neighbour_idx] => {
+              visited[neighbour_idx] = true;
+              stack.push(neighbour_idx);
+            },
+            Some(p) if p != pattern => {
+              border_patterns.insert(p);
+            },
+            _ => {},
+          }
+        }
+      }
+      if region.len() >= min_size {
+        continue;
+      }
+
+      let candidates: Vec<usize> = border_patterns.into_iter().filter(|p| self.is_valid_replacement(&region, *p)).collect();
+      if let Some(replacement) = replacement_selector(&candidates) {
+        self.mark_dirty(region.iter().copied());
+        for idx in region {
+          self.place(idx, replacement);
+          self.options[idx] = pattern_set(self.patterns.len(), [replacement]);
+        }
+        replaced += 1;
+      }
+    }
+
+    replaced
+  }
+
+  fn is_valid_replacement(&self, region: &[usize], pattern: usize) -> bool {
+    region.iter().all(|idx| {
+      self.neighbourhood.offsets().iter().all(|(dx, dy)| {
+        let Some(neighbour_idx) = self.neighbour_index(*idx, *dx, *dy) else {
+          return true;
+        };
+        let neighbour_pattern = if region.contains(&neighbour_idx) { Some(pattern) } else { self.cell(neighbour_idx) };
+        match neighbour_pattern {
+          Some(p) => self.adjacency_rules[pattern].get(&(*dx, *dy)).unwrap().contains(&p),
+          None => true,
+        }
+      })
+    })
+  }
+}
+
+pub struct Steps<'a, P: Drawable + Clone, I: PatternIndex = u16> {
+  grid: &'a mut Grid<P, I>,
+  done: bool,
+}
+
+impl<P: Drawable + Clone, I: PatternIndex> Steps<'_, P, I> {
+  pub fn grid(&self) -> &Grid<P, I> {
+    self.grid
+  }
+}
+
+impl<P: Drawable + Clone, I: PatternIndex> Iterator for Steps<'_, P, I> {
+  type Item = Result<StepOutcome, WfcError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.done {
+      return None;
+    }
+    let outcome = self.grid.step();
+    self.done = !matches!(outcome, Ok(StepOutcome::Collapsed) | Ok(StepOutcome::Backtracked));
+    Some(outcome)
+  }
+}
+
+// a fully collapsed grid stripped of all solver state, keeps only what drawing and export need
+#[derive(Clone)]
+pub struct SolvedGrid<P: Drawable + Clone> {
+  pub width: usize,
+  pub height: usize,
+  pub cells: Vec<usize>,
+  pub patterns: Rc<P>,
+}
+
+impl<P: Drawable + Clone> SolvedGrid<P> {
+  #[cfg(feature = "render")]
+  pub fn draw(&self) {
+    for (index, pattern) in self.cells.iter().enumerate() {
+      let (x, y) = xy_from_index(index, self.width);
+      self.patterns.draw(x as f32 * TILE_SIZE + GRID_OFFSET, y as f32 * TILE_SIZE + GRID_OFFSET, *pattern);
+    }
+  }
+
+  // `WfcError::ImageTooLarge` when either side doesn't fit the `u16` of an `Image`
+  pub fn to_image(&self) -> Result<Image, WfcError> {
+    let size = TILE_SIZE as usize;
+    let (width, height) = (self.width * size, self.height * size);
+    let (Ok(image_width), Ok(image_height)) = (u16::try_from(width), u16::try_from(height)) else {
+      return Err(WfcError::ImageTooLarge { width, height });
+    };
+    let mut image = Image::gen_image_color(image_width, image_height, BLANK);
+    for (index, pattern) in self.cells.iter().enumerate() {
+      let (x, y) = xy_from_index(index, self.width);
+      self.patterns.rasterize(&mut image, (x * size) as u32, (y * size) as u32, *pattern);
+    }
+    Ok(image)
+  }
+
+  pub fn export_png(&self, path: &str) -> Result<(), WfcError> {
+    self.to_image()?.export_png(path);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+
+  // patterns that are nothing but ids, for rulesets written out by hand
+  #[derive(Clone)]
+  pub(crate) struct Ids(pub(crate) usize);
+
+  impl Drawable for Ids {
+    #[cfg(feature = "render")]
+    fn draw(&self, _x: f32, _y: f32, _idx: usize) {}
+    fn rasterize(&self, _target: &mut Image, _x: u32, _y: u32, _idx: usize) {}
+    fn len(&self) -> usize {
+      self.0
+    }
+  }
+
+  // the same neighbours at every offset, `allowed(p)` lists what may sit next to `p`
+  pub(crate) fn rules(pattern_count: usize, offsets: &[(isize, isize)], allowed: impl Fn(usize) -> Vec<usize>) -> Rc<AdjacencyData> {
+    Rc::new((0..pattern_count).map(|p| offsets.iter().map(|d| (*d, allowed(p))).collect()).collect())
+  }
+
+  // 0 next to 0 or 1, 1 next to anything, 2 next to 1 or 2
+  pub(crate) fn three_bands() -> Rc<AdjacencyData> {
+    rules(3, &N_INDEXES, |p| match p {
+      0 => vec![0, 1],
+      1 => vec![0, 1, 2],
+      _ => vec![1, 2],
+    })
+  }
+
+  // every collapsed pair of neighbours is allowed by the rules
+  pub(crate) fn is_valid<P: Drawable + Clone, I: PatternIndex>(grid: &Grid<P, I>) -> bool {
+    (0..grid.cells.len()).all(|idx| {
+      grid.neighbourhood.offsets().iter().all(|(dx, dy)| {
+        match (grid.cell(idx), grid.neighbour_index(idx, *dx, *dy).and_then(|n| grid.cell(n))) {
+          (Some(p), Some(n)) => grid.adjacency_rules[p][&(*dx, *dy)].contains(&n),
+          _ => true,
+        }
+      })
+    })
+  }
+
+  #[test]
+  fn backtracks_several_levels() {
+    // three colours on a wrapping grid, a choice can leave a cell with two differently coloured
+    // neighbours that only shows up steps later, so backtracking often has to undo several of
+    // them. With the whole history kept it always gets back to a solvable state
+    let rules = rules(3, &N_INDEXES, |p| (0..3).filter(|n| *n != p).collect());
+    let mut deep_backtracks = 0;
+    for seed in 0..100 {
+      let mut grid = Grid::new_with_seed(8, 8, &rules, &Rc::new(Ids(3)), seed)
+        .with_wrap(true)
+        .with_history_length(None);
+      let mut backtracked = false;
+      loop {
+        match grid.step() {
+          Ok(StepOutcome::Finished) => break,
+          Ok(StepOutcome::Backtracked) => {
+            if backtracked {
+              deep_backtracks += 1;
+            }
+            backtracked = true;
+          },
+          Ok(StepOutcome::Collapsed) => backtracked = false,
+          Err(error) => panic!("seed {}: {:?}", seed, error),
+        }
+      }
+      assert!(is_valid(&grid));
+    }
+    assert!(deep_backtracks > 0);
+  }
+
+  #[test]
+  fn unwind_keeps_earlier_exclusions() {
+    let mut grid = Grid::new_with_seed(3, 1, &three_bands(), &Rc::new(Ids(3)), 0)
+      .with_collapse_strategy(CollapseStrategy::LowestId)
+      .with_history_length(None);
+    grid.set_cell_priority(vec![3., 1., 2.], true);
+    grid.step().unwrap();
+    grid.step().unwrap();
+    assert_eq!(grid.get(2, 0), Some(0));
+    // as if pattern 0 at cell 2 had led to a contradiction
+    grid.unwind();
+    assert!(!grid.options[2].contains(0));
+    // a step elsewhere that changes cell 2 without collapsing it first...
+    grid.set_cell_priority(vec![3., 2., 1.], true);
+    grid.step().unwrap();
+    assert_eq!(grid.get(1, 0), Some(0));
+    // ...undone again mustn't offer the excluded pattern there
+    grid.unwind();
+    assert!(!grid.options[2].contains(0));
+  }
+
+  #[test]
+  fn reports_forced_patterns() {
+    let mut grid = Grid::new_with_seed(3, 1, &three_bands(), &Rc::new(Ids(3)), 0);
+    grid.constrain_cell(0, 0, &[0]).unwrap();
+    // 0 only borders 0 and 1, banning 1 next to it leaves 0
+    grid.ban(1, 1).unwrap();
+    assert_eq!(grid.forced_pattern_at(0, 0), Some(0));
+    assert_eq!(grid.forced_pattern_at(1, 0), Some(0));
+    assert_eq!(grid.forced_pattern_at(2, 0), None);
+    // the last cell is chosen by observe
+    grid.step().unwrap();
+    assert!(grid.get(2, 0).is_some());
+    assert_eq!(grid.forced_pattern_at(2, 0), None);
+  }
+
+  #[test]
+  fn zero_weight_patterns_are_never_placed() {
+    let mut grid = Grid::new_with_seed(3, 1, &three_bands(), &Rc::new(Ids(3)), 0).with_pattern_weights(vec![1., 0., 1.]);
+    grid.set_cell(0, 0, 0).unwrap();
+    // 0 and 2 only fit with 1 between them, which can't be picked
+    grid.set_cell(2, 0, 2).unwrap();
+    assert!(matches!(grid.run(), Err(WfcError::Contradiction { x: 1, y: 0, .. })));
+    assert_eq!(grid.get(1, 0), None);
+  }
+
+  #[test]
+  fn generate_reports_every_cell_once_across_restarts() {
+    let rules = rules(3, &N_INDEXES, |p| (0..3).filter(|n| *n != p).collect());
+    let mut restarted = false;
+    for seed in 0..20 {
+      // with a short history, contradictions on the wrapping grid often can't be backtracked
+      let mut grid = Grid::new_with_seed(8, 8, &rules, &Rc::new(Ids(3)), seed)
+        .with_wrap(true)
+        .with_history_length(Some(1));
+      let reported = Rc::new(RefCell::new(vec![]));
+      let sink = Rc::clone(&reported);
+      grid.set_on_finalize(Box::new(move |x, y, p| sink.borrow_mut().push((x, y, p))));
+      let Ok(restarts) = grid.generate(50) else {
+        continue;
+      };
+      restarted |= restarts > 0;
+      let mut reported = reported.take();
+      reported.sort_unstable();
+      let expected: Vec<(usize, usize, usize)> = (0..8).flat_map(|x| (0..8).map(move |y| (x, y))).map(|(x, y)| (x, y, grid.get(x, y).unwrap())).collect();
+      assert_eq!(reported, expected);
+    }
+    assert!(restarted);
+  }
+
+  #[test]
+  fn maximum_set_while_generating() {
+    let mut grid = Grid::new_with_seed(4, 4, &three_bands(), &Rc::new(Ids(3)), 0).with_collapse_strategy(CollapseStrategy::LowestId);
+    // the first step builds the observe heap, the cells losing 2 have to be queued again
+    grid.step().unwrap();
+    grid.require_at_most(2, 0);
+    grid.run().unwrap();
+    assert_eq!(grid.pattern_histogram()[2], 0);
+  }
+
+  #[test]
+  fn directional_weight_continues_paths() {
+    // 0 is grass and 1 a path, anything fits anywhere
+    let rules = rules(2, &N_INDEXES, |_| vec![0, 1]);
+    let (mut below_path, mut below_grass) = ((0, 0), (0, 0));
+    for seed in 0..10 {
+      // row by row, so the cell above is always decided first
+      let mut grid = Grid::new_with_seed(8, 8, &rules, &Rc::new(Ids(2)), seed).with_tie_break(TieBreak::FirstIndex);
+      grid.set_directional_weight(1, (0, -1), 1, 20.);
+      grid.run().unwrap();
+      for (x, y) in (1..8).flat_map(|y| (0..8).map(move |x| (x, y))) {
+        let counts = if grid.get(x, y - 1) == Some(1) { &mut below_path } else { &mut below_grass };
+        counts.0 += usize::from(grid.get(x, y) == Some(1));
+        counts.1 += 1;
+      }
+    }
+    assert!(below_path.0 as f64 / below_path.1 as f64 > 0.8);
+    assert!((below_grass.0 as f64 / below_grass.1 as f64) < 0.7);
+  }
+
+  #[test]
+  fn enumerates_every_solution() {
+    let mut grid = Grid::new_with_seed(2, 1, &three_bands(), &Rc::new(Ids(3)), 0);
+    let mut solutions = grid.enumerate_solutions(usize::MAX);
+    solutions.sort();
+    assert_eq!(solutions, vec![vec![0, 0], vec![0, 1], vec![1, 0], vec![1, 1], vec![1, 2], vec![2, 1], vec![2, 2]]);
+    assert_eq!(grid.enumerate_solutions(3).len(), 3);
+  }
+
+  #[test]
+  fn enumerating_leaves_the_grid_as_it_was() {
+    let mut grid = Grid::new_with_seed(3, 2, &three_bands(), &Rc::new(Ids(3)), 7);
+    let mut untouched = Grid::new_with_seed(3, 2, &three_bands(), &Rc::new(Ids(3)), 7);
+    grid.step().unwrap();
+    untouched.step().unwrap();
+    assert!(!grid.enumerate_solutions(usize::MAX).is_empty());
+    grid.run().unwrap();
+    untouched.run().unwrap();
+    assert_eq!(grid.to_indices(), untouched.to_indices());
+  }
+
+  #[test]
+  fn consistency_check_finds_contradictory_edges() {
+    let rules = three_bands();
+    let patterns = Rc::new(Ids(3));
+    let mut grid: Grid<Ids> = Grid::new(5, 1, &rules, &patterns);
+    assert!(grid.quick_consistency_check());
+    // 0 and 2 at the ends, the 1 between them fits
+    grid.options[0] = pattern_set(3, [0]);
+    grid.options[4] = pattern_set(3, [2]);
+    assert!(grid.quick_consistency_check());
+    // 0 right next to 2 can't be
+    grid.options[1] = pattern_set(3, [2]);
+    assert!(!grid.quick_consistency_check());
+    // only a scratch copy is narrowed
+    assert_eq!(grid.options[2].count_ones(..), 3);
+  }
+
+  #[test]
+  fn image_too_large_for_an_image() {
+    let solved = SolvedGrid { width: 4096, height: 1, cells: vec![0; 4096], patterns: Rc::new(Ids(1)) };
+    assert_eq!(solved.to_image().err(), Some(WfcError::ImageTooLarge { width: 4096 * TILE_SIZE as usize, height: TILE_SIZE as usize }));
+    let solved = SolvedGrid { width: 2, height: 1, cells: vec![0; 2], patterns: Rc::new(Ids(1)) };
+    let image = solved.to_image().unwrap();
+    assert_eq!((image.width as usize, image.height as usize), (2 * TILE_SIZE as usize, TILE_SIZE as usize));
+  }
+
+  // pins the exact output for a seed, a change that alters it breaks this on purpose (and has to
+  // update the expected cells)
+  #[test]
+  fn golden_output() {
+    let mut grid: Grid<Ids> = Grid::new_with_seed(8, 4, &three_bands(), &Rc::new(Ids(3)), 42);
+    grid.run().unwrap();
+    assert_eq!(grid.to_indices().unwrap(), vec![
+      1, 1, 0, 0, 1, 1, 2, 2,
+      2, 2, 1, 0, 1, 2, 1, 1,
+      1, 1, 1, 1, 2, 1, 1, 0,
+      2, 2, 2, 2, 2, 1, 1, 0,
+    ]);
+    let mut grid: Grid<Ids> = Grid::new_with_seed(8, 4, &three_bands(), &Rc::new(Ids(3)), 42)
+      .with_tie_break(TieBreak::FirstIndex)
+      .with_collapse_strategy(CollapseStrategy::LowestId);
+    grid.constrain_cell(5, 1, &[2]).unwrap();
+    grid.constrain_cell(1, 3, &[2]).unwrap();
+    grid.run().unwrap();
+    // without the RNG, the same for any seed
+    assert_eq!(grid.to_indices().unwrap(), vec![
+      0, 0, 0, 0, 0, 1, 0, 0,
+      0, 0, 0, 0, 1, 2, 1, 0,
+      0, 1, 0, 0, 0, 1, 0, 0,
+      1, 2, 1, 0, 0, 0, 0, 0,
+    ]);
+  }
+
+  #[test]
+  fn removes_small_regions() {
+    let patterns = Rc::new(Ids(3));
+    let mut grid: Grid<Ids> = Grid::new(3, 3, &three_bands(), &patterns);
+    for idx in 0..9 {
+      grid.place(idx, 1);
+    }
+    grid.place(4, 0);
+    assert_eq!(grid.remove_small_regions(2, |candidates| candidates.first().copied()), 1);
+    assert!((0..9).all(|idx| grid.cell(idx) == Some(1)));
+    // a region of min_size stays
+    grid.place(4, 0);
+    grid.place(5, 0);
+    assert_eq!(grid.remove_small_regions(2, |candidates| candidates.first().copied()), 0);
+    assert_eq!(grid.cell(4), Some(0));
+
+    // neither neighbour fits next to the other one
+    let mut grid: Grid<Ids> = Grid::new(5, 1, &three_bands(), &patterns);
+    for (idx, pattern) in [0, 0, 1, 2, 2].into_iter().enumerate() {
+      grid.place(idx, pattern);
+    }
+    assert_eq!(grid.remove_small_regions(2, |candidates| candidates.first().copied()), 0);
+    assert_eq!(grid.to_indices(), Some(vec![0, 0, 1, 2, 2]));
+  }
+
+  #[test]
+  fn noise_weights_shape_the_output() {
+    let rules = three_bands();
+    let patterns = Rc::new(Ids(3));
+    let (mut left, mut right) = (0, 0);
+    for seed in 0..10 {
+      let mut grid: Grid<Ids> = Grid::new_with_seed(10, 10, &rules, &patterns, seed);
+      // high on the left, where it favours 0
+      grid.apply_noise_weights(|x, _| 1. - x, |value| if value > 0.5 { vec![(0, 100.)] } else { vec![(2, 100.)] });
+      grid.run().unwrap();
+      left += (0..100).filter(|idx| idx % 10 < 4 && grid.cell(*idx) == Some(0)).count();
+      right += (0..100).filter(|idx| idx % 10 >= 6 && grid.cell(*idx) == Some(0)).count();
+    }
+    assert!(left > 5 * right, "{} on the left, {} on the right", left, right);
+  }
+
+  #[test]
+  fn grids_share_one_ruleset() {
+    let rules = three_bands();
+    let patterns = Rc::new(Ids(3));
+    let mut grids: Vec<Grid<Ids>> = (0..100).map(|seed| Grid::new_with_seed(4, 4, &rules, &patterns, seed)).collect();
+    assert_eq!(Rc::strong_count(&rules), 101);
+    assert_eq!(Rc::strong_count(&patterns), 101);
+    assert!(grids.iter().all(|grid| Rc::ptr_eq(&grid.adjacency_rules, &rules) && Rc::ptr_eq(&grid.patterns, &patterns)));
+    grids[0].run().unwrap();
+    assert!(grids[0].is_finished());
+    assert!(grids[1..].iter().all(|grid| grid.cells.iter().all(Option::is_none)));
+    drop(grids);
+    assert_eq!(Rc::strong_count(&rules), 1);
+  }
+
+  #[test]
+  fn keeps_groups_apart() {
+    let rules = three_bands();
+    let patterns = Rc::new(Ids(3));
+    for seed in 0..20 {
+      let mut grid: Grid<Ids> = Grid::new_with_seed(8, 8, &rules, &patterns, seed);
+      grid.min_spacing(&[0], &[2], 2);
+      // both groups show up
+      grid.constrain_cell(0, 0, &[0]).unwrap();
+      grid.constrain_cell(7, 7, &[2]).unwrap();
+      grid.run().unwrap();
+      assert!(is_valid(&grid));
+      for idx in (0..64).filter(|idx| grid.cell(*idx) == Some(0)) {
+        assert!(grid.cells_within(idx, 2).iter().all(|near| grid.cell(*near) != Some(2)), "seed {}", seed);
+      }
+    }
+  }
+
+  #[test]
+  fn reports_pattern_positions() {
+    let mut grid: Grid<Ids> = Grid::new(3, 2, &three_bands(), &Rc::new(Ids(3)));
+    for (idx, pattern) in [0, 1, 1, 2, 1, 0].into_iter().enumerate() {
+      grid.place(idx, pattern);
+    }
+    let positions = grid.pattern_positions();
+    assert_eq!(positions.len(), 3);
+    assert_eq!(positions[&0], vec![(0, 0), (2, 1)]);
+    assert_eq!(positions[&1], vec![(1, 0), (2, 0), (1, 1)]);
+    assert_eq!(positions[&2], vec![(0, 1)]);
+  }
+
+  #[test]
+  fn max_impact_observe() {
+    let rules = three_bands();
+    let patterns = Rc::new(Ids(3));
+    let steps = |strategy: ObserveStrategy| {
+      let mut total = 0;
+      for seed in 0..10 {
+        let mut grid: Grid<Ids> = Grid::new_with_seed(8, 8, &rules, &patterns, seed).with_observe_strategy(strategy);
+        while !grid.is_finished() {
+          grid.step().unwrap();
+          total += 1;
+        }
+        assert!(is_valid(&grid));
+      }
+      total
+    };
+    let (max_impact, min_entropy) = (steps(ObserveStrategy::MaxImpact), steps(ObserveStrategy::MinEntropy));
+    assert!(max_impact < min_entropy, "{} steps with max impact, {} with min entropy", max_impact, min_entropy);
+  }
+
+  #[test]
+  fn finalizes_a_finished_grid() {
+    let patterns = Rc::new(Ids(3));
+    let grid: Grid<Ids> = Grid::new_with_seed(6, 4, &three_bands(), &patterns, 1);
+    assert!(matches!(grid.finalize(), Err(WfcError::NotFinished)));
+    let mut grid: Grid<Ids> = Grid::new_with_seed(6, 4, &three_bands(), &patterns, 1);
+    grid.run().unwrap();
+    let cells = grid.to_indices().unwrap();
+    let Ok(solved) = grid.finalize() else {
+      panic!("a finished grid should finalize");
+    };
+    assert_eq!((solved.width, solved.height), (6, 4));
+    assert_eq!(solved.cells, cells);
+    let copy = solved.clone();
+    assert!(Rc::ptr_eq(&copy.patterns, &solved.patterns));
+    assert_eq!(copy.to_image().unwrap().bytes, solved.to_image().unwrap().bytes);
+    assert!(std::mem::size_of::<SolvedGrid<Ids>>() < std::mem::size_of::<Grid<Ids>>() / 4);
+  }
+
+  #[test]
+  fn local_retry_beats_restarting() {
+    // three colours without backtracking, contradictions are common but local
+    let rules = rules(3, &N_INDEXES, |p| (0..3).filter(|n| *n != p).collect());
+    let patterns = Rc::new(Ids(3));
+    let grid = |seed| Grid::<Ids>::new_with_seed(12, 12, &rules, &patterns, seed).with_history_length(Some(0));
+    let (mut local_steps, mut restart_steps) = (0, 0);
+    for seed in 0..10 {
+      // the smallest budget local retry finishes in is the steps it took
+      let (mut low, mut high) = (1, 100_000);
+      assert!(grid(seed).generate_with_local_retry(3, 2, high));
+      while low < high {
+        let budget = (low + high) / 2;
+        if grid(seed).generate_with_local_retry(3, 2, budget) {
+          high = budget;
+        } else {
+          low = budget + 1;
+        }
+      }
+      let mut retried = grid(seed);
+      assert!(retried.generate_with_local_retry(3, 2, low));
+      assert!(is_valid(&retried));
+      local_steps += low;
+
+      let mut restarted = grid(seed);
+      while !restarted.is_finished() {
+        if restarted.step().is_err() {
+          restarted.reset();
+        }
+        restart_steps += 1;
+      }
+    }
+    assert!(local_steps < restart_steps, "{} steps retrying locally, {} restarting", local_steps, restart_steps);
+  }
+
+  #[test]
+  fn biome_seams_prefer_transitions() {
+    // grass 0 and 3, water 2 and 4 and coast 1 next to everything. 0 and 2 may touch, so the
+    // cell next to grass can still turn into anything but 4
+    let rules = rules(5, &N_INDEXES, |p| match p {
+      0 => vec![0, 1, 2, 3],
+      1 => vec![0, 1, 2, 3, 4],
+      2 => vec![0, 1, 2, 4],
+      3 => vec![0, 1, 3],
+      _ => vec![1, 2, 4],
+    });
+    let patterns = Rc::new(Ids(5));
+    let coasts = |boost: Option<f64>| {
+      (0..200).filter(|seed| {
+        let mut grid: Grid<Ids> = Grid::new_with_seed(2, 1, &rules, &patterns, *seed);
+        if let Some(boost) = boost {
+          grid.set_biome_groups(vec![vec![0, 3], vec![2, 4]], boost);
+        }
+        grid.constrain_cell(0, 0, &[0]).unwrap();
+        grid.run().unwrap();
+        grid.cell(1) == Some(1)
+      }).count()
+    };
+    let (plain, boosted) = (coasts(None), coasts(Some(10.)));
+    assert!(boosted > plain * 3 / 2, "{} coasts with the boost, {} without", boosted, plain);
+  }
+
+  #[test]
+  fn reports_collapsed_neighbours() {
+    let mut grid: Grid<Ids> = Grid::new(3, 3, &three_bands(), &Rc::new(Ids(3)));
+    grid.place(1, 0);
+    grid.place(3, 1);
+    grid.place(8, 2);
+    // up, right, down, left
+    assert_eq!(grid.neighbors_collapsed(4), [Some(0), None, None, Some(1)]);
+    // off the grid like undecided ones
+    assert_eq!(grid.neighbors_collapsed(0), [None, Some(0), Some(1), None]);
+  }
+
+  #[test]
+  fn constrains_corners() {
+    let patterns = Rc::new(Ids(3));
+    for seed in 0..10 {
+      let mut grid: Grid<Ids> = Grid::new_with_seed(5, 4, &three_bands(), &patterns, seed);
+      grid.constrain_corners(&[2]).unwrap();
+      grid.run().unwrap();
+      assert!(is_valid(&grid));
+      assert!([(0, 0), (4, 0), (0, 3), (4, 3)].iter().all(|(x, y)| grid.get(*x, *y) == Some(2)));
+    }
+    let mut grid: Grid<Ids> = Grid::new(3, 1, &three_bands(), &patterns);
+    grid.constrain_corners(&[0]).unwrap();
+    assert!(matches!(grid.constrain_corners(&[2]), Err(WfcError::Contradiction { .. })));
+  }
+
+  #[test]
+  fn propagates_along_custom_offsets() {
+    let knight = vec![(1, 2), (2, 1), (2, -1), (1, -2), (-1, -2), (-2, -1), (-2, 1), (-1, 2)];
+    // 0 only a knight's move away from 1, 1 anywhere
+    let rules = rules(2, &knight, |p| if p == 0 { vec![1] } else { vec![0, 1] });
+    let mut grid: Grid<Ids> = Grid::new(5, 5, &rules, &Rc::new(Ids(2))).with_neighbours(knight.clone());
+    grid.constrain_cell(2, 2, &[0]).unwrap();
+    for idx in 0..25 {
+      let (x, y) = xy_from_index(idx, 5);
+      let offset = (x as isize - 2, y as isize - 2);
+      let expected = if offset == (0, 0) {
+        vec![0]
+      } else if knight.contains(&offset) {
+        vec![1]
+      } else {
+        vec![0, 1]
+      };
+      assert_eq!(grid.options[idx].ones().collect::<Vec<usize>>(), expected, "{}, {}", x, y);
+    }
+  }
+
+  #[test]
+  fn exports_tiled_csv() {
+    let mut grid: Grid<Ids> = Grid::new(3, 2, &three_bands(), &Rc::new(Ids(3)));
+    for (idx, pattern) in [0, 1, 2, 1, 1].into_iter().enumerate() {
+      grid.place(idx, pattern);
+    }
+    // rows end with a comma except the last, like Tiled writes them
+    assert_eq!(grid.export_tiled_csv(&[5, 6, 7]), "5,6,7,\n6,6,0\n");
+  }
+
+  #[test]
+  fn follows_a_target_histogram() {
+    let rules = rules(3, &N_INDEXES, |_| vec![0, 1, 2]);
+    for seed in 0..5 {
+      let mut grid: Grid<Ids> = Grid::new_with_seed(20, 20, &rules, &Rc::new(Ids(3)), seed);
+      grid.set_target_histogram(&[(0, 0.6), (1, 0.3), (2, 0.1)]);
+      grid.run().unwrap();
+      let histogram = grid.pattern_histogram();
+      for (pattern, share) in [0.6, 0.3, 0.1].into_iter().enumerate() {
+        assert!((histogram[pattern] as f64 / 400. - share).abs() < 0.05, "seed {}: {:?}", seed, histogram);
+      }
+    }
+  }
+
+  #[test]
+  fn viewport_covers_only_visible_cells() {
+    let grid: Grid<Ids> = Grid::new(5, 4, &three_bands(), &Rc::new(Ids(3)));
+    assert_eq!(grid.viewport_indices(Rect::new(3., 2., 5., 5.)), vec![13, 14, 18, 19]);
+    // partly visible cells count
+    assert_eq!(grid.viewport_indices(Rect::new(0.5, 0.5, 1., 0.2)), vec![0, 1]);
+    assert_eq!(grid.viewport_indices(Rect::new(-2., -2., 3., 3.)), vec![0]);
+    assert!(grid.viewport_indices(Rect::new(10., 0., 3., 3.)).is_empty());
+  }
+
+  #[test]
+  fn observes_higher_priority_cells_first() {
+    let patterns = Rc::new(Ids(3));
+    let mut priorities = vec![0.; 25];
+    priorities[12] = 5.;
+    for seed in 0..10 {
+      let mut grid: Grid<Ids> = Grid::new_with_seed(5, 5, &three_bands(), &patterns, seed);
+      grid.set_cell_priority(priorities.clone(), false);
+      grid.step().unwrap();
+      assert!(grid.cell(12).is_some());
+      // a cell with fewer options still goes first...
+      let mut grid: Grid<Ids> = Grid::new_with_seed(5, 5, &three_bands(), &patterns, seed);
+      grid.constrain_cell(0, 0, &[0, 1]).unwrap();
+      grid.set_cell_priority(priorities.clone(), false);
+      grid.step().unwrap();
+      assert!(grid.cell(0).is_some());
+      // ...unless priority overrides entropy
+      let mut grid: Grid<Ids> = Grid::new_with_seed(5, 5, &three_bands(), &patterns, seed);
+      grid.constrain_cell(0, 0, &[0, 1]).unwrap();
+      grid.set_cell_priority(priorities.clone(), true);
+      grid.step().unwrap();
+      assert!(grid.cell(12).is_some() && grid.cell(0).is_none());
+    }
+  }
+
+  #[test]
+  fn requires_a_minimum_count() {
+    let rules = three_bands();
+    let patterns = Rc::new(Ids(3));
+    for seed in 0..10 {
+      let decorations = |require: bool| {
+        let mut grid: Grid<Ids> = Grid::new_with_seed(6, 6, &rules, &patterns, seed).with_history_length(None);
+        // 0 is all but certain everywhere
+        grid.apply_noise_weights(|_, _| 0., |_| vec![(0, 1000.), (1, 0.001), (2, 0.001)]);
+        if require {
+          grid.require_at_least(2, 3);
+        }
+        grid.run().unwrap();
+        assert!(is_valid(&grid));
+        grid.pattern_histogram()[2]
+      };
+      assert_eq!(decorations(false), 0);
+      assert!(decorations(true) >= 3);
+    }
+  }
+
+  #[test]
+  fn propagation_stops_at_the_radius() {
+    // 1 between every 0 and 2, without a radius a single cell narrows the whole grid
+    let rules = rules(3, &N_INDEXES, |p| if p == 1 { vec![0, 2] } else { vec![1] });
+    let mut grid: Grid<Ids> = Grid::new(7, 7, &rules, &Rc::new(Ids(3))).with_propagation_radius(Some(1));
+    grid.set_cell(3, 3, 0).unwrap();
+    for idx in 0..49 {
+      let (x, y) = xy_from_index(idx, 7);
+      let expected = match x.abs_diff(3) + y.abs_diff(3) {
+        0 => vec![0],
+        1 => vec![1],
+        _ => vec![0, 1, 2],
+      };
+      assert_eq!(grid.options[idx].ones().collect::<Vec<usize>>(), expected, "{}, {}", x, y);
+    }
+    grid.propagate_deferred().unwrap();
+    assert_eq!(grid.options[index_from_xy(5, 3, 7)].ones().collect::<Vec<usize>>(), vec![0, 2]);
+    assert_eq!(grid.options[index_from_xy(6, 3, 7)].ones().collect::<Vec<usize>>(), vec![1]);
+  }
+
+  #[test]
+  fn propagation_reaches_the_same_fixpoint_in_any_order() {
+    let rules = three_bands();
+    let patterns = Rc::new(Ids(3));
+    let bans = [(10, 1), (30, 1), (30, 0), (52, 2), (52, 1)];
+    let mut forwards: Grid<Ids> = Grid::new(9, 7, &rules, &patterns);
+    let mut backwards: Grid<Ids> = Grid::new(9, 7, &rules, &patterns);
+    for (idx, pattern) in bans {
+      forwards.ban(idx, pattern).unwrap();
+    }
+    for (idx, pattern) in bans.into_iter().rev() {
+      backwards.ban(idx, pattern).unwrap();
+    }
+    assert_eq!(forwards.options, backwards.options);
+    // every option left has support in each neighbour
+    for idx in 0..forwards.cells.len() {
+      for (dx, dy) in N_INDEXES {
+        let Some(neighbour_idx) = forwards.neighbour_index(idx, dx, dy) else {
+          continue;
+        };
+        assert!(forwards.options[idx].ones().all(|p| rules[p][&(dx, dy)].iter().any(|n| forwards.options[neighbour_idx].contains(*n))));
+      }
+    }
+    let settled = forwards.options.clone();
+    forwards.propagate_from((0..forwards.cells.len()).rev().collect());
+    assert_eq!(forwards.options, settled);
+  }
+
+  #[test]
+  fn constrained_edge_survives_backtracking() {
+    // the three colour ruleset from above with the top row kept to two of them, backtracking
+    // must never hand the third back to the edge
+    let rules = rules(3, &N_INDEXES, |p| (0..3).filter(|n| *n != p).collect());
+    let mut backtracked = false;
+    for seed in 0..100 {
+      let mut grid = Grid::new_with_seed(8, 8, &rules, &Rc::new(Ids(3)), seed)
+        .with_wrap(true)
+        .with_history_length(None);
+      grid.constrain_edge(Edge::Top, &[0, 1]).unwrap();
+      loop {
+        match grid.step() {
+          Ok(StepOutcome::Finished) => break,
+          Ok(StepOutcome::Backtracked) => backtracked = true,
+          Ok(StepOutcome::Collapsed) => {},
+          Err(error) => panic!("seed {}: {:?}", seed, error),
+        }
+        assert!((0..8).all(|x| !grid.options[x].contains(2)));
+      }
+      assert!(is_valid(&grid));
+      assert!((0..8).all(|x| grid.get(x, 0) != Some(2)));
+    }
+    assert!(backtracked);
+  }
+
+  #[test]
+  fn picks_off_target_patterns_adjacency_forces() {
+    // no two neighbours share a colour, so every cell next to a 0 has to take a pattern the
+    // histogram leaves out
+    let rules = rules(3, &N_INDEXES, |p| (0..3).filter(|n| *n != p).collect());
+    for seed in 0..5 {
+      let mut grid: Grid<Ids> = Grid::new_with_seed(8, 8, &rules, &Rc::new(Ids(3)), seed);
+      grid.set_target_histogram(&[(0, 1.)]);
+      grid.run().unwrap();
+      assert!(is_valid(&grid));
+      let histogram = grid.pattern_histogram();
+      assert!(histogram[0] > histogram[1] && histogram[0] > histogram[2], "seed {}: {:?}", seed, histogram);
+    }
+  }
+
+  #[test]
+  fn retried_cells_see_neighbours_across_the_seam() {
+    // the left column's neighbour on the right edge is only reached by wrapping
+    let mut grid: Grid<Ids> = Grid::new(4, 3, &three_bands(), &Rc::new(Ids(3))).with_wrap(true);
+    for idx in 0..12 {
+      grid.place(idx, 1);
+    }
+    grid.place(index_from_xy(3, 1, 4), 2);
+    let idx = index_from_xy(0, 1, 4);
+    grid.retry_cell(idx, 0);
+    assert_eq!(grid.options[idx].ones().collect::<Vec<_>>(), vec![1, 2]);
+  }
+
+  #[test]
+  fn filler_fills_open_areas() {
+    // 0 is grass, the left edge is pinned to 2 which can't touch grass, so 1 has to sit between
+    // them. 1 outweighs 2 so the pinned area doesn't keep spreading
+    for seed in 0..5 {
+      let mut grid: Grid<Ids> = Grid::new_with_seed(10, 10, &three_bands(), &Rc::new(Ids(3)), seed).with_pattern_weights(vec![1., 10., 1.]);
+      grid.set_filler(0, 1000.);
+      grid.constrain_edge(Edge::Left, &[2]).unwrap();
+      grid.run().unwrap();
+      assert!(is_valid(&grid));
+      assert!((0..10).all(|y| grid.get(0, y) == Some(2) && grid.get(1, y) != Some(0)));
+      assert!(grid.pattern_histogram()[0] >= 70, "seed {}: {:?}", seed, grid.pattern_histogram());
+    }
+  }
+
+  #[test]
+  fn entropy_heap_picks_from_the_full_scan_ties() {
+    let rules = rules(3, &N_INDEXES, |p| (0..3).filter(|n| *n != p).collect());
+    for tie_break in [TieBreak::Random, TieBreak::FirstIndex] {
+      for seed in 0..5 {
+        let mut grid = Grid::new_with_seed(8, 8, &rules, &Rc::new(Ids(3)), seed)
+          .with_wrap(true)
+          .with_history_length(None)
+          .with_pattern_weights(vec![1., 2., 4.])
+          .with_tie_break(tie_break);
+        while !grid.is_finished() {
+          let (_, ties) = grid.scan_lowest_entropy();
+          let idx = grid.observe();
+          assert!(ties.contains(&idx), "seed {}: {} not in {:?}", seed, idx, ties);
+          if tie_break == TieBreak::FirstIndex {
+            assert_eq!(idx, ties[0]);
+          }
+          // observe took the cell off the heap, the step observes again
+          grid.track_entropy(idx);
+          grid.step().unwrap();
+        }
+        assert!(is_valid(&grid));
+      }
+    }
+  }
+
+  #[test]
+  fn places_exactly_one_landmark() {
+    // 2 is the landmark, with 1 around it
+    let rules = three_bands();
+    for seed in 0..10 {
+      let mut grid: Grid<Ids> = Grid::new_with_seed(8, 8, &rules, &Rc::new(Ids(3)), seed).with_history_length(None);
+      grid.require_at_least(2, 1);
+      grid.require_at_most(2, 1);
+      grid.run().unwrap();
+      assert!(is_valid(&grid));
+      assert_eq!(grid.pattern_histogram()[2], 1, "seed {}", seed);
+    }
+  }
+
+  #[test]
+  fn draws_the_grid_as_ascii() {
+    let mut grid: Grid<Ids> = Grid::new(3, 2, &three_bands(), &Rc::new(Ids(3)));
+    for (idx, pattern) in [0, 1, 2, 1, 2].into_iter().enumerate() {
+      grid.place(idx, pattern);
+    }
+    // 2 is past the charset, the last cell is undecided
+    assert_eq!(grid.to_ascii(&['.', '~']), ".~#\n~#?\n");
+  }
+
+  #[test]
+  fn enumerates_zero_weight_patterns() {
+    // collapse never picks 1, the search still has to place it
+    let mut grid = Grid::new_with_seed(2, 1, &three_bands(), &Rc::new(Ids(3)), 0).with_pattern_weights(vec![1., 0., 1.]);
+    let mut solutions = grid.enumerate_solutions(usize::MAX);
+    solutions.sort();
+    assert_eq!(solutions, vec![vec![0, 0], vec![0, 1], vec![1, 0], vec![1, 1], vec![1, 2], vec![2, 1], vec![2, 2]]);
+  }
+}
@@ -0,0 +1,153 @@
+use crate::utils::{axial_from_index, checked_neighbour, index_from_axial, index_from_xy, xy_from_index, HEX_INDEXES, N_INDEXES, N_INDEXES_DIAGONAL};
+
+// the topology of a grid: which directions adjacency rules are keyed by and how a cell index
+// steps along them. `Grid` and the preprocessors only go through this, so a new topology is a
+// new impl
+pub trait Neighbourhood {
+  // direction offsets, the adjacency rules need an entry for each of them
+  fn offsets(&self) -> &[(isize, isize)];
+
+  // names hand-written rules may use for `offsets`, in the same order
+  fn direction_names(&self) -> &[&'static str] {
+    &[]
+  }
+
+  // index into `offsets` of the direction pointing back, by default the negated offset
+  fn opposite(&self, direction: usize) -> usize {
+    let (dx, dy) = self.offsets()[direction];
+    self.offsets().iter().position(|offset| *offset == (-dx, -dy)).expect("neighbourhood without an opposite direction")
+  }
+
+  // cell reached from `idx` by `offset`, `None` when it falls off a grid that doesn't wrap
+  fn neighbour(&self, idx: usize, (dx, dy): (isize, isize), width: usize, height: usize, wrap: bool) -> Option<usize> {
+    if !wrap {
+      return checked_neighbour(idx, dx, dy, width, height);
+    }
+    let (x, y) = xy_from_index(idx, width);
+    let nx = (x as isize + dx).rem_euclid(width as isize);
+    let ny = (y as isize + dy).rem_euclid(height as isize);
+    Some(index_from_xy(nx as usize, ny as usize, width))
+  }
+}
+
+// up, right, down, left
+pub struct Square4;
+
+impl Neighbourhood for Square4 {
+  fn offsets(&self) -> &[(isize, isize)] {
+    &N_INDEXES
+  }
+
+  fn direction_names(&self) -> &[&'static str] {
+    &["up", "right", "down", "left"]
+  }
+
+  fn opposite(&self, direction: usize) -> usize {
+    (direction + 2) % 4
+  }
+}
+
+// `Square4` plus the diagonals, clockwise from up
+pub struct Square8;
+
+impl Neighbourhood for Square8 {
+  fn offsets(&self) -> &[(isize, isize)] {
+    &N_INDEXES_DIAGONAL
+  }
+
+  fn opposite(&self, direction: usize) -> usize {
+    (direction + 4) % 8
+  }
+}
+
+// pointy-top hexagons, cells are stored row by row with odd rows shifted half a hex to the right
+// and offsets are axial (see `axial_from_index`). Wrapping is seamless vertically only for even
+// heights, an odd one puts two rows with the same shift next to each other
+pub struct Hex;
+
+impl Neighbourhood for Hex {
+  fn offsets(&self) -> &[(isize, isize)] {
+    &HEX_INDEXES
+  }
+
+  fn direction_names(&self) -> &[&'static str] {
+    &["east", "northeast", "northwest", "west", "southwest", "southeast"]
+  }
+
+  fn opposite(&self, direction: usize) -> usize {
+    (direction + 3) % 6
+  }
+
+  fn neighbour(&self, idx: usize, (dq, dr): (isize, isize), width: usize, height: usize, wrap: bool) -> Option<usize> {
+    let (q, r) = axial_from_index(idx, width);
+    let (mut q, mut r) = (q + dq, r + dr);
+    if wrap {
+      let x = (q + r.div_euclid(2)).rem_euclid(width as isize);
+      r = r.rem_euclid(height as isize);
+      q = x - r.div_euclid(2);
+    }
+    index_from_axial(q, r, width, height)
+  }
+}
+
+// arbitrary offsets on a square grid, e.g. knight moves
+impl Neighbourhood for Vec<(isize, isize)> {
+  fn offsets(&self) -> &[(isize, isize)] {
+    self
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::rc::Rc;
+
+  use super::*;
+  use crate::{Grid, tests::{Ids, is_valid, rules}};
+
+  // stepping along a direction and back along its opposite returns to every cell
+  fn assert_symmetric(neighbourhood: &dyn Neighbourhood, width: usize, height: usize, wrap: bool) {
+    for idx in 0..width * height {
+      for (direction, offset) in neighbourhood.offsets().iter().enumerate() {
+        let Some(neighbour) = neighbourhood.neighbour(idx, *offset, width, height, wrap) else {
+          continue;
+        };
+        let back = neighbourhood.offsets()[neighbourhood.opposite(direction)];
+        assert_eq!(neighbourhood.neighbour(neighbour, back, width, height, wrap), Some(idx), "{} towards {:?}", idx, offset);
+      }
+    }
+  }
+
+  #[test]
+  fn hex_offsets_are_symmetric() {
+    assert_symmetric(&Hex, 6, 5, false);
+    assert_symmetric(&Hex, 6, 6, true);
+    assert_symmetric(&Square8, 5, 4, false);
+    assert_symmetric(&Square8, 5, 4, true);
+    // odd rows sit half a hex to the right, so the cells above an even row are at x - 1 and x and
+    // those above an odd row at x and x + 1
+    let neighbours = |x, y| {
+      let idx = index_from_xy(x, y, 6);
+      Hex.offsets().iter().map(|offset| Hex.neighbour(idx, *offset, 6, 6, false).map(|n| xy_from_index(n, 6))).collect::<Vec<_>>()
+    };
+    assert_eq!(neighbours(2, 2), [(3, 2), (2, 1), (1, 1), (1, 2), (1, 3), (2, 3)].map(Some));
+    assert_eq!(neighbours(2, 1), [(3, 1), (3, 0), (2, 0), (1, 1), (2, 2), (3, 2)].map(Some));
+  }
+
+  #[test]
+  fn generates_on_six_and_eight_neighbours() {
+    let bands = |p| match p {
+      0 => vec![0, 1],
+      1 => vec![0, 1, 2],
+      _ => vec![1, 2],
+    };
+    let neighbourhoods: [Rc<dyn Neighbourhood>; 2] = [Rc::new(Hex), Rc::new(Square8)];
+    for neighbourhood in neighbourhoods {
+      let rules = rules(3, neighbourhood.offsets(), bands);
+      for seed in 0..5 {
+        let mut grid: Grid<Ids> = Grid::new_with_seed(8, 8, &rules, &Rc::new(Ids(3)), seed).with_neighbourhood(Rc::clone(&neighbourhood));
+        grid.run().unwrap();
+        assert!(is_valid(&grid));
+      }
+    }
+  }
+}
@@ -1,47 +1,228 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, rc::Rc, sync::atomic::AtomicUsize};
 
 use macroquad::prelude::*;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 
-use crate::{utils::{rotate_image, N_INDEXES, AdjacencyData}, Drawable, TILE_SIZE, WfcPreprocessor};
+use crate::{utils::{rotate_image, report_progress, AdjacencyData, ProgressCallback}, neighbourhood::{Neighbourhood, Square4, Square8}, Drawable, TILE_SIZE, WfcPreprocessor, PreprocessError};
 
 #[derive(Clone)]
 pub struct ColorPattern(Vec<Color>);
 
 impl Drawable for ColorPattern {
+  #[cfg(feature = "render")]
   fn draw(&self, x: f32, y: f32, idx: usize) {
     draw_rectangle(x, y, TILE_SIZE, TILE_SIZE, self.0[idx]);
   }
+  fn rasterize(&self, target: &mut Image, x: u32, y: u32, idx: usize) {
+    let size = TILE_SIZE as u32;
+    for py in y..(y + size).min(target.height() as u32) {
+      for px in x..(x + size).min(target.width() as u32) {
+        target.set_pixel(px, py, self.0[idx]);
+      }
+    }
+  }
+  #[cfg(feature = "render")]
+  fn draw_superposition(&self, x: f32, y: f32, options: &[(usize, f64)]) {
+    if let Some(color) = self.blend(options) {
+      draw_rectangle(x, y, TILE_SIZE, TILE_SIZE, color);
+    }
+  }
   fn len(&self) -> usize {
     self.0.len()
   }
 }
 
+impl ColorPattern {
+  // weighted average of the option colors, so high-weight options dominate the preview
+  pub fn blend(&self, options: &[(usize, f64)]) -> Option<Color> {
+    let total: f64 = options.iter().map(|(_, w)| w).sum();
+    if total <= 0. {
+      return None;
+    }
+    let mut channels = [0.; 4];
+    for (idx, weight) in options {
+      let c = self.0[*idx];
+      for (channel, value) in channels.iter_mut().zip([c.r, c.g, c.b, c.a]) {
+        *channel += value as f64 * weight / total;
+      }
+    }
+    Some(Color::new(channels[0] as f32, channels[1] as f32, channels[2] as f32, channels[3] as f32))
+  }
+
+  // indexes of the patterns drawn as `color`, e.g. for `Grid::constrain_edge`
+  pub fn patterns_with_color(&self, color: Color) -> Vec<usize> {
+    self.0.iter().enumerate().filter(|(_, c)| **c == color).map(|(idx, _)| idx).collect()
+  }
+}
+
+// how a non-wrapping sample is virtually extended by `n - 1` pixels on each side before
+// extraction, so patterns at its borders get seen in more contexts
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PaddingMode {
+  None,
+  Clamp,
+  Mirror,
+}
+
 pub struct OverlappingPreprocessor {
   n: isize,
   wrap_w: bool,
   wrap_h: bool,
-  rotate: bool
+  rotate: bool,
+  padding: PaddingMode,
+  progress: Option<(ProgressCallback, usize)>,
+  frequency_order: bool,
+  diagonals: bool,
 }
 
 impl OverlappingPreprocessor {
   pub fn new(n: isize, wrap_w: bool, wrap_h: bool, rotate: bool) -> Self {
-    Self { n, wrap_w, wrap_h, rotate }
+    Self { n, wrap_w, wrap_h, rotate, padding: PaddingMode::None, progress: None, frequency_order: false, diagonals: false }
+  }
+
+  pub fn with_padding(mut self, padding: PaddingMode) -> Self {
+    self.padding = padding;
+    self
+  }
+
+  // extracted patterns are ordered by descending occurrence count (ties by pixel hash), so
+  // pattern 0 is always the most common one
+  pub fn with_frequency_order(mut self, frequency_order: bool) -> Self {
+    self.frequency_order = frequency_order;
+    self
+  }
+
+  // adjacency rules cover the diagonal offsets as well, the grid then has to be given the same
+  // topology through `Grid::with_neighbourhood(processor.neighbourhood())`
+  pub fn with_diagonals(mut self, diagonals: bool) -> Self {
+    self.diagonals = diagonals;
+    self
+  }
+
+  // topology the adjacency rules are computed for
+  pub fn neighbourhood(&self) -> Rc<dyn Neighbourhood> {
+    if self.diagonals {
+      Rc::new(Square8)
+    } else {
+      Rc::new(Square4)
+    }
+  }
+
+  // `callback` gets `(done, total)` every `every` patterns while adjacency rules are computed
+  pub fn with_progress(mut self, callback: ProgressCallback, every: usize) -> Self {
+    self.progress = Some((callback, every.max(1)));
+    self
+  }
+
+  // how many n x n windows `extract_images` takes from `image`. Along an axis that neither wraps
+  // nor is padded only windows fully inside the sample are taken, so its last `n - 1` columns or
+  // rows are covered solely as parts of windows starting further left or up
+  pub fn window_count(&self, image: &Image) -> Result<usize, PreprocessError> {
+    let (width, height) = self.window_grid(image)?;
+    Ok(width as usize * height as usize)
+  }
+
+  // the windows `sample_windows` takes as columns by rows
+  fn window_grid(&self, image: &Image) -> Result<(u16, u16), PreprocessError> {
+    let n = self.n as u16;
+    if (!self.wrap_w && image.width < n) || (!self.wrap_h && image.height < n) {
+      return Err(PreprocessError::ImageTooSmall { width: image.width, height: image.height, n });
+    }
+    // padding adds `n - 1` on both sides of a non-wrapping axis
+    let windows = |size: u16, wrap: bool| match (wrap, self.padding) {
+      (true, _) => size,
+      (false, PaddingMode::None) => size - n + 1,
+      (false, _) => size + n - 1,
+    };
+    Ok((windows(image.width, self.wrap_w), windows(image.height, self.wrap_h)))
+  }
+
+  // every n x n window of the (padded) sample as (width, height, windows), windows are ordered
+  // column by column so the one at x, y is at `x * height + y`
+  fn sample_windows(&self, image: &Image) -> Result<(u16, u16, Vec<Image>), PreprocessError> {
+    let n = self.n as u16;
+    let (width, height) = self.window_grid(image)?;
+    let padded;
+    let image = if self.padding == PaddingMode::None {
+      image
+    } else {
+      let pad_w = if self.wrap_w { 0 } else { n - 1 };
+      let pad_h = if self.wrap_h { 0 } else { n - 1 };
+      padded = pad_image(image, pad_w, pad_h, self.padding);
+      &padded
+    };
+
+    let windows = (0..width)
+      .flat_map(|x| (0..height).map(move |y| (x, y)))
+      .map(|(x, y)| get_pattern_image(image, self.n, x, y))
+      .collect();
+    Ok((width, height, windows))
+  }
+
+  // the sample has to be a valid output of the rules derived from it, so every window is mapped
+  // to its pattern and each pair of neighbouring windows checked against the rules, gives the
+  // positions of windows breaking one (none for a consistent sample)
+  pub fn verify_sample(&self, image: &Image) -> Result<Vec<(usize, usize)>, PreprocessError> {
+    let images = self.extract_images(image)?;
+    let (width, height, windows) = self.sample_windows(image)?;
+    let rules = self.create_adjacency_rules(&images);
+    let index: HashMap<&[u8], usize> = images.iter().enumerate().map(|(idx, img)| (img.bytes.as_slice(), idx)).collect();
+    let window_patterns: Vec<Option<usize>> = windows.iter().map(|img| index.get(img.bytes.as_slice()).copied()).collect();
+    let (width, height) = (width as isize, height as isize);
+    let neighbours = self.neighbourhood().offsets().to_vec();
+
+    let mut violations = vec![];
+    for x in 0..width {
+      for y in 0..height {
+        let pattern = window_patterns[(x * height + y) as usize];
+        let valid = neighbours.iter().all(|(ox, oy)| {
+          let (mut nx, mut ny) = (x + ox, y + oy);
+          if self.wrap_w {
+            nx = nx.rem_euclid(width);
+          }
+          if self.wrap_h {
+            ny = ny.rem_euclid(height);
+          }
+          if nx < 0 || nx >= width || ny < 0 || ny >= height {
+            return true;
+          }
+          match (pattern, window_patterns[(nx * height + ny) as usize]) {
+            (Some(p), Some(q)) => rules[p][&(*ox, *oy)].contains(&q),
+            _ => false,
+          }
+        });
+        if !valid {
+          violations.push((x as usize, y as usize));
+        }
+      }
+    }
+    Ok(violations)
   }
 }
 
 impl WfcPreprocessor for OverlappingPreprocessor {
   type Pattern = ColorPattern;
 
+  // a cell shows the center pixel of its window like in the canonical overlapping model, any
+  // fixed anchor agrees with the rules (neighbouring cells are windows shifted by one pixel)
   fn create_patterns(&self, images: &[Image]) -> Self::Pattern {
-    let patterns: Vec<Color> = images.iter().map(|i| i.get_pixel(0, 0)).collect();
+    let anchor = (self.n / 2) as u32;
+    let patterns: Vec<Color> = images.iter().map(|i| i.get_pixel(anchor, anchor)).collect();
     ColorPattern(patterns)
   }
 
   fn create_adjacency_rules(&self, images: &[Image]) -> AdjacencyData {
-    let mut texture_overlaps = vec![];
-    for img in images {
+    let done = AtomicUsize::new(0);
+    let neighbours = self.neighbourhood().offsets().to_vec();
+    // every pattern's adjacency is computed on its own with `parallel`, collected in order either way
+    #[cfg(feature = "parallel")]
+    let images_iter = images.par_iter();
+    #[cfg(not(feature = "parallel"))]
+    let images_iter = images.iter();
+    images_iter.map(|img| {
       let mut overlap_pattern = HashMap::new();
-      for (ox, oy) in N_INDEXES {
+      for (ox, oy) in neighbours.iter().copied() {
         let mut valid_tex_idxes = vec![];
         for (overlap_idx, overlap_img) in images.iter().enumerate() {
           let mut valid = true;
@@ -64,35 +245,77 @@ impl WfcPreprocessor for OverlappingPreprocessor {
         valid_tex_idxes.reverse();
         overlap_pattern.insert((ox, oy), valid_tex_idxes);
       }
-      texture_overlaps.push(overlap_pattern);
-    }
-    texture_overlaps
+      report_progress(&self.progress, &done, images.len());
+      overlap_pattern
+    }).collect()
   }
 
-  fn extract_images(&self, image: &Image) -> Vec<Image> {
-    let mut images: Vec<Image> = vec![];
+  fn config_key(&self) -> String {
+    format!("overlapping n={} wrap={},{} rotate={} padding={:?} frequency_order={} diagonals={}", self.n, self.wrap_w, self.wrap_h, self.rotate, self.padding, self.frequency_order, self.diagonals)
+  }
 
-    let width = if self.wrap_w { image.width } else { image.width - self.n as u16 };
-    let height = if self.wrap_h { image.height } else { image.height - self.n as u16 };
+  fn extract_images(&self, image: &Image) -> Result<Vec<Image>, PreprocessError> {
+    let (images, _) = self.extract_weighted_images(image)?;
+    Ok(images)
+  }
 
-    for x in 0..width {
-      for y in 0..height {
-        let img = get_pattern_image(image, self.n, x, y);
-        if self.rotate {
-          for rot in 1..4 {
-            let rotated_image = rotate_image(&img, rot);
-            if images.iter().all(|p| p.get_image_data() != rotated_image.get_image_data()) {
-              images.push(rotated_image);
-            }
-          }
-        }
-        if images.iter().all(|p| p.get_image_data() != img.get_image_data()) {
-          images.push(img);
+  // weights are each pattern's share of all extracted windows (rotations included)
+  fn extract_weighted_images(&self, image: &Image) -> Result<(Vec<Image>, Vec<f32>), PreprocessError> {
+    let mut images: Vec<Image> = vec![];
+    let mut counts: Vec<usize> = vec![];
+    let mut seen: HashMap<Vec<u8>, usize> = HashMap::new();
+    let (_, _, windows) = self.sample_windows(image)?;
+
+    for img in windows {
+      if self.rotate {
+        for rot in 1..4 {
+          add_occurrence(&mut images, &mut counts, &mut seen, rotate_image(&img, rot));
         }
       }
+      add_occurrence(&mut images, &mut counts, &mut seen, img);
+    }
+    if self.frequency_order {
+      // ties are ordered by their pixel bytes, so the order doesn't depend on the toolchain
+      let mut ordered: Vec<(usize, Image)> = counts.into_iter().zip(images).collect();
+      ordered.sort_by(|(count_a, img_a), (count_b, img_b)| count_b.cmp(count_a).then(img_a.bytes.cmp(&img_b.bytes)));
+      (counts, images) = ordered.into_iter().unzip();
+    }
+    let total: usize = counts.iter().sum();
+    let weights = counts.iter().map(|count| *count as f32 / total as f32).collect();
+    Ok((images, weights))
+  }
+}
+
+// `seen` maps pixel data to its index in `images`, so new images keep first-seen order
+fn add_occurrence(images: &mut Vec<Image>, counts: &mut Vec<usize>, seen: &mut HashMap<Vec<u8>, usize>, img: Image) {
+  if let Some(idx) = seen.get(&img.bytes) {
+    counts[*idx] += 1;
+  } else {
+    seen.insert(img.bytes.clone(), images.len());
+    images.push(img);
+    counts.push(1);
+  }
+}
+
+fn pad_image(image: &Image, pad_w: u16, pad_h: u16, mode: PaddingMode) -> Image {
+  let source_coord = |c: u16, pad: u16, size: u16| -> u32 {
+    let c = c as isize - pad as isize;
+    let size = size as isize;
+    let c = match mode {
+      PaddingMode::Mirror if c < 0 => -c - 1,
+      PaddingMode::Mirror if c >= size => 2 * size - c - 1,
+      _ => c,
+    };
+    c.clamp(0, size - 1) as u32
+  };
+  let mut padded = Image::gen_image_color(image.width + 2 * pad_w, image.height + 2 * pad_h, WHITE);
+  for x in 0..padded.width {
+    for y in 0..padded.height {
+      let color = image.get_pixel(source_coord(x, pad_w, image.width), source_coord(y, pad_h, image.height));
+      padded.set_pixel(x as u32, y as u32, color);
     }
-    images
   }
+  padded
 }
 
 fn get_pattern_image(src_image: &Image, n: isize, x: u16, y: u16) -> Image {
@@ -109,3 +332,157 @@ fn get_pattern_image(src_image: &Image, n: isize, x: u16, y: u16) -> Image {
   }
   pattern_image
 }
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+
+  use super::*;
+
+  #[test]
+  fn sample_smaller_than_a_window() {
+    let processor = OverlappingPreprocessor::new(3, false, false, false);
+    let image = Image::gen_image_color(2, 2, WHITE);
+    assert!(matches!(processor.extract_images(&image), Err(PreprocessError::ImageTooSmall { width: 2, height: 2, n: 3 })));
+    assert!(matches!(processor.window_count(&image), Err(PreprocessError::ImageTooSmall { .. })));
+    // wrapping around, every pixel starts a window
+    assert_eq!(OverlappingPreprocessor::new(3, true, true, false).window_count(&image).unwrap(), 4);
+  }
+
+  #[test]
+  fn counts_windows() {
+    let image = Image::gen_image_color(4, 3, WHITE);
+    let cases = [
+      (OverlappingPreprocessor::new(2, false, false, false), 3 * 2),
+      (OverlappingPreprocessor::new(2, true, false, false), 4 * 2),
+      (OverlappingPreprocessor::new(2, false, false, false).with_padding(PaddingMode::Clamp), 5 * 4),
+    ];
+    for (processor, count) in cases {
+      assert_eq!(processor.window_count(&image).unwrap(), count);
+      assert_eq!(processor.sample_windows(&image).unwrap().2.len(), count);
+    }
+  }
+
+  // a colour as it comes back out of an image
+  fn stored(color: Color) -> Color {
+    Image::gen_image_color(1, 1, color).get_pixel(0, 0)
+  }
+
+  #[test]
+  fn rasterizes_a_tile_of_the_pattern_colour() {
+    let patterns = ColorPattern(vec![stored(RED), stored(BLUE)]);
+    let mut target = Image::gen_image_color(40, 40, WHITE);
+    patterns.rasterize(&mut target, 10, 10, 1);
+    let size = TILE_SIZE as u32;
+    assert_eq!(target.get_pixel(10, 10), stored(BLUE));
+    assert_eq!(target.get_pixel(9 + size, 9 + size), stored(BLUE));
+    assert_eq!(target.get_pixel(9, 9), stored(WHITE));
+    assert_eq!(target.get_pixel(10 + size, 10 + size), stored(WHITE));
+    // clipped at the edge of the target
+    patterns.rasterize(&mut target, 32, 32, 0);
+    assert_eq!(target.get_pixel(39, 39), stored(RED));
+  }
+
+  #[test]
+  fn padding_reduces_contradictions() {
+    // a lone blue pixel in the corner, unpadded it only shows up in windows at the sample's edge
+    let mut image = Image::gen_image_color(5, 5, RED);
+    image.set_pixel(0, 0, BLUE);
+    let contradictions = |padding: PaddingMode| {
+      let (patterns, rules) = crate::process(&OverlappingPreprocessor::new(3, false, false, false).with_padding(padding), &image).unwrap();
+      let (rules, patterns) = (Rc::new(rules), Rc::new(patterns));
+      (0..10).map(|seed| {
+        let mut grid: crate::Grid<ColorPattern> = crate::Grid::new_with_seed(30, 30, &rules, &patterns, seed);
+        grid.run().unwrap();
+        grid.contradiction_counts.iter().sum::<usize>()
+      }).sum::<usize>()
+    };
+    let (unpadded, padded) = (contradictions(PaddingMode::None), contradictions(PaddingMode::Clamp));
+    assert!(padded < unpadded, "{} contradictions padded, {} without", padded, unpadded);
+  }
+
+  #[test]
+  fn reports_adjacency_progress() {
+    let mut image = Image::gen_image_color(4, 4, RED);
+    image.set_pixel(1, 1, BLUE);
+    let reported = Arc::new(Mutex::new(vec![]));
+    let sink = Arc::clone(&reported);
+    let processor = OverlappingPreprocessor::new(2, true, true, false).with_progress(Arc::new(move |done, total| sink.lock().unwrap().push((done, total))), 2);
+    let (_, rules) = crate::process(&processor, &image).unwrap();
+    // patterns are done in parallel, so in any order
+    let mut reported = reported.lock().unwrap().clone();
+    reported.sort_unstable();
+    let every_second: Vec<(usize, usize)> = (1..=rules.len()).filter(|done| done % 2 == 0 || *done == rules.len()).map(|done| (done, rules.len())).collect();
+    assert_eq!(reported, every_second);
+  }
+
+  #[test]
+  fn orders_patterns_by_frequency() {
+    let mut image = Image::gen_image_color(6, 6, RED);
+    image.set_pixel(5, 5, BLUE);
+    image.set_pixel(0, 0, GREEN);
+    let ordered = OverlappingPreprocessor::new(2, true, true, false).with_frequency_order(true);
+    let (images, weights) = ordered.extract_weighted_images(&image).unwrap();
+    assert!(weights.windows(2).all(|pair| pair[0] >= pair[1]));
+    // equally common windows by their pixels
+    assert!((1..images.len()).all(|i| weights[i - 1] > weights[i] || images[i - 1].bytes < images[i].bytes));
+    // the plain red window is the most common one
+    assert!(images[0].get_image_data().iter().all(|pixel| *pixel == image.get_image_data()[1]));
+
+    // the same adjacencies between the same windows, only numbered differently
+    let relation = |processor: &OverlappingPreprocessor| {
+      let images = processor.extract_images(&image).unwrap();
+      let mut pairs: Vec<_> = processor.create_adjacency_rules(&images).iter().enumerate()
+        .flat_map(|(p, adjacencies)| adjacencies.iter().flat_map(move |(direction, neighbours)| neighbours.iter().map(move |n| (p, *direction, *n))))
+        .map(|(p, direction, n)| (images[p].bytes.clone(), direction, images[n].bytes.clone()))
+        .collect();
+      pairs.sort();
+      pairs
+    };
+    assert_eq!(relation(&ordered), relation(&OverlappingPreprocessor::new(2, true, true, false)));
+  }
+
+  #[test]
+  fn patterns_take_the_centre_colour() {
+    let image = Image::gen_image_color(5, 5, RED);
+    let (patterns, rules) = crate::process(&OverlappingPreprocessor::new(3, false, false, true), &image).unwrap();
+    let mut grid: crate::Grid<ColorPattern> = crate::Grid::new_with_seed(4, 4, &Rc::new(rules), &Rc::new(patterns), 0);
+    grid.run().unwrap();
+    let Ok(solved) = grid.finalize() else {
+      panic!("a finished grid should finalize");
+    };
+    let output = solved.to_image().unwrap();
+    assert!(output.get_image_data().iter().all(|pixel| *pixel == image.get_image_data()[0]));
+
+    let mut dot = Image::gen_image_color(3, 3, RED);
+    dot.set_pixel(1, 1, BLUE);
+    let patterns = OverlappingPreprocessor::new(3, false, false, false).create_patterns(&[dot.clone()]);
+    assert_eq!(patterns.0, vec![dot.get_pixel(1, 1)]);
+  }
+
+  #[test]
+  fn verifies_a_clean_sample() {
+    let mut image = Image::gen_image_color(6, 5, WHITE);
+    for (x, y) in [(1, 1), (2, 1), (4, 3), (0, 4)] {
+      image.set_pixel(x, y, RED);
+    }
+    for processor in [OverlappingPreprocessor::new(3, false, false, false), OverlappingPreprocessor::new(2, true, true, true)] {
+      assert_eq!(processor.verify_sample(&image).unwrap(), vec![]);
+    }
+    let tiny = Image::gen_image_color(2, 2, WHITE);
+    assert!(matches!(OverlappingPreprocessor::new(3, false, false, false).verify_sample(&tiny), Err(PreprocessError::ImageTooSmall { .. })));
+  }
+
+  #[test]
+  fn blends_options_by_weight() {
+    let patterns = ColorPattern(vec![RED, BLUE]);
+    let blended = patterns.blend(&[(0, 9.), (1, 1.)]).unwrap();
+    let expected = [0.9 * RED.r + 0.1 * BLUE.r, 0.9 * RED.g + 0.1 * BLUE.g, 0.9 * RED.b + 0.1 * BLUE.b, 1.];
+    for (channel, value) in [blended.r, blended.g, blended.b, blended.a].into_iter().zip(expected) {
+      assert!((channel - value).abs() < 1e-5, "{:?}", blended);
+    }
+    // mostly red
+    assert!(blended.r > 0.8 && blended.b < 0.3);
+    assert_eq!(patterns.blend(&[(0, 0.), (1, 0.)]), None);
+  }
+}
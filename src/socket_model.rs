@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use macroquad::prelude::*;
+
+use crate::{WfcPreprocessor, PreprocessError, tile_model::TexturePattern, utils::{rotate_image, AdjacencyData}, neighbourhood::{Neighbourhood, Square4}};
+
+// one segment of a tile edge, `Any` connects to every other socket
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Socket {
+  Named(String),
+  Any,
+}
+
+impl Socket {
+  // an edge from whitespace separated socket names, `*` stands for `Any`
+  pub fn edge(text: &str) -> Vec<Socket> {
+    text.split_whitespace()
+      .map(|name| if name == "*" { Socket::Any } else { Socket::Named(name.to_owned()) })
+      .collect()
+  }
+
+  fn connects(&self, other: &Socket) -> bool {
+    match (self, other) {
+      (Socket::Any, _) | (_, Socket::Any) => true,
+      (Socket::Named(a), Socket::Named(b)) => a == b,
+    }
+  }
+}
+
+// the four edges of a tile in `Square4` order (top, right, bottom, left), each read clockwise
+// around the tile, so rotating the tile just shifts them
+pub type TileSockets = [Vec<Socket>; 4];
+
+// touching edges run in opposite directions when both are read clockwise, so `b` is compared
+// reversed, segment by segment
+pub fn sockets_compatible(a: &[Socket], b: &[Socket]) -> bool {
+  a.len() == b.len() && a.iter().zip(b.iter().rev()).all(|(sa, sb)| sa.connects(sb))
+}
+
+// tiles whose adjacency comes from the sockets described for each of their edges instead of
+// their pixels. Tiles are read row by row from the sheet and `sockets` has an entry for each
+pub struct SocketProcessor {
+  tile_size: f32,
+  rotate: bool,
+  sockets: Vec<TileSockets>,
+}
+
+impl SocketProcessor {
+  pub fn new(tile_size: f32, rotate: bool, sockets: Vec<TileSockets>) -> Self {
+    Self { tile_size, rotate, sockets }
+  }
+
+  // tile and clockwise quarter turns of every extracted image, rotations that leave all sockets
+  // unchanged are skipped. Worked out from the sockets alone, so `extract_images` and
+  // `create_adjacency_rules` agree on it
+  fn orientations(&self) -> Vec<(usize, usize)> {
+    let mut orientations = vec![];
+    for tile in 0..self.sockets.len() {
+      let mut kept: Vec<TileSockets> = vec![];
+      for rot in 0..if self.rotate { 4 } else { 1 } {
+        let rotated = self.rotated_sockets(tile, rot);
+        if !kept.contains(&rotated) {
+          kept.push(rotated);
+          orientations.push((tile, rot));
+        }
+      }
+    }
+    orientations
+  }
+
+  fn rotated_sockets(&self, tile: usize, rot: usize) -> TileSockets {
+    let sockets = &self.sockets[tile];
+    std::array::from_fn(|side| sockets[(side + 4 - rot) % 4].clone())
+  }
+}
+
+impl WfcPreprocessor for SocketProcessor {
+  type Pattern = TexturePattern;
+
+  fn extract_images(&self, image: &Image) -> Result<Vec<Image>, PreprocessError> {
+    let tile_size = self.tile_size as u16;
+    let (columns, rows) = (image.width / tile_size, image.height / tile_size);
+    let tiles = columns as usize * rows as usize;
+    if tiles != self.sockets.len() {
+      return Err(PreprocessError::SocketCount { tiles, sockets: self.sockets.len() });
+    }
+    Ok(self.orientations().into_iter().map(|(tile, rot)| {
+      let (x, y) = (tile % columns as usize, tile / columns as usize);
+      let img = image.sub_image(Rect::new(x as f32 * self.tile_size, y as f32 * self.tile_size, self.tile_size, self.tile_size));
+      if rot == 0 { img } else { rotate_image(&img, rot) }
+    }).collect())
+  }
+
+  fn create_patterns(&self, images: &[Image]) -> Self::Pattern {
+    let groups: Vec<Vec<usize>> = (0..images.len()).map(|idx| vec![idx]).collect();
+    TexturePattern::from_groups(images, &groups)
+  }
+
+  fn create_adjacency_rules(&self, images: &[Image]) -> AdjacencyData {
+    let sockets: Vec<TileSockets> = self.orientations().into_iter().map(|(tile, rot)| self.rotated_sockets(tile, rot)).collect();
+    assert_eq!(sockets.len(), images.len(), "images should come from `extract_images` of the same processor");
+    let neighbourhood = Square4;
+
+    sockets.iter().map(|source| {
+      let mut adjacencies: HashMap<(isize, isize), Vec<usize>> = HashMap::new();
+      for (direction, offset) in neighbourhood.offsets().iter().enumerate() {
+        let opposite = neighbourhood.opposite(direction);
+        let targets = sockets.iter().enumerate()
+          .filter(|(_, target)| sockets_compatible(&source[direction], &target[opposite]))
+          .map(|(target_idx, _)| target_idx)
+          .collect();
+        adjacencies.insert(*offset, targets);
+      }
+      adjacencies
+    }).collect()
+  }
+
+  fn config_key(&self) -> String {
+    format!("socket size={} rotate={} sockets={:?}", self.tile_size, self.rotate, self.sockets)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn compatible_sockets() {
+    let edge = Socket::edge;
+    assert!(sockets_compatible(&edge("grass road sand"), &edge("sand road grass")));
+    assert!(!sockets_compatible(&edge("grass road sand"), &edge("grass road sand")));
+    assert!(sockets_compatible(&edge("* road *"), &edge("sand road grass")));
+    assert!(!sockets_compatible(&edge("grass road"), &edge("road grass grass")));
+  }
+
+  #[test]
+  fn roads_connect_by_their_sockets() {
+    let edge = Socket::edge;
+    // grass, a road ending on the left and a straight road
+    let sockets = vec![
+      [edge("g g g"), edge("g g g"), edge("g g g"), edge("g g g")],
+      [edge("g g g"), edge("g g g"), edge("g g g"), edge("* r *")],
+      [edge("g g g"), edge("g r g"), edge("g g g"), edge("g r g")],
+    ];
+    let processor = SocketProcessor::new(4., true, sockets);
+    let sheet = Image::gen_image_color(12, 4, WHITE);
+    let images = processor.extract_images(&sheet).unwrap();
+    // grass once, the road end in all four turns and the straight road in two
+    assert_eq!(images.len(), 7);
+    let rules = processor.create_adjacency_rules(&images);
+    // the unturned road end continues into the horizontal road on its left, grass can't go there
+    assert!(rules[1][&(-1, 0)].contains(&5));
+    assert!(!rules[1][&(-1, 0)].contains(&0));
+    assert!(rules[0][&(1, 0)].contains(&0));
+    for (pattern, adjacencies) in rules.iter().enumerate() {
+      for ((dx, dy), neighbours) in adjacencies {
+        assert!(neighbours.iter().all(|n| rules[*n][&(-dx, -dy)].contains(&pattern)));
+      }
+    }
+    assert!(matches!(SocketProcessor::new(4., false, vec![]).extract_images(&sheet), Err(PreprocessError::SocketCount { tiles: 3, sockets: 0 })));
+  }
+}
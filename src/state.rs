@@ -0,0 +1,148 @@
+use std::{collections::{BinaryHeap, VecDeque}, rc::Rc};
+
+use serde::{Serialize, Deserialize, de::Error};
+
+use crate::{Drawable, EntropyEntry, Grid, HistoryEntry, utils::{AdjacencyData, PatternIndex, SeededRng, pattern_set}};
+
+// choice, updated tiles, options before the step and exclusions of a `HistoryEntry`
+type SavedStep = ((usize, usize), Vec<usize>, Vec<usize>, Vec<(usize, usize)>);
+// entropy, priority, tiebreak and cell of an `EntropyEntry`
+type SavedEntry = (f64, f32, u32, usize);
+
+// everything a grid changes while generating, bitsets are stored as their set pattern indexes.
+// Configuration (weights, strategies, callbacks, ...) isn't part of it and has to be applied to
+// the loaded grid again
+#[derive(Serialize, Deserialize)]
+struct GridState {
+  width: usize,
+  height: usize,
+  pattern_count: usize,
+  cells: Vec<Option<usize>>,
+  options: Vec<Vec<usize>>,
+  banned: Vec<Vec<usize>>,
+  history: Vec<SavedStep>,
+  excluded: Vec<(usize, usize)>,
+  contradiction_counts: Vec<usize>,
+  last_contradiction: Option<(usize, Option<(isize, isize)>)>,
+  emptied_by: Vec<(usize, (isize, isize))>,
+  // in the heap's own order, its tiebreaks came from the RNG so they can't be drawn again
+  entropy_heap: Option<Vec<SavedEntry>>,
+  finalized: Vec<bool>,
+  forced: Vec<bool>,
+  deferred_propagation: Vec<usize>,
+  rng: u64,
+}
+
+impl<P: Drawable + Clone, I: PatternIndex> Grid<P, I> {
+  // the generation state as JSON, patterns and rules are left out and given back to `load`
+  pub fn save(&self) -> String {
+    let state = GridState {
+      width: self.width,
+      height: self.height,
+      pattern_count: self.patterns.len(),
+      cells: self.cells.iter().map(|c| c.map(PatternIndex::pattern)).collect(),
+      options: self.options.iter().map(|options| options.ones().collect()).collect(),
+      banned: self.banned.iter().map(|banned| banned.iter().copied().collect()).collect(),
+      history: self.history.iter()
+        .map(|entry| (entry.choice, entry.updated_tiles.iter().copied().collect(), entry.options.ones().collect(), entry.excluded.clone()))
+        .collect(),
+      excluded: self.excluded.clone(),
+      contradiction_counts: self.contradiction_counts.clone(),
+      last_contradiction: self.last_contradiction,
+      emptied_by: {
+        let mut emptied_by: Vec<(usize, (isize, isize))> = self.emptied_by.iter().map(|(idx, direction)| (*idx, *direction)).collect();
+        emptied_by.sort_unstable();
+        emptied_by
+      },
+      entropy_heap: self.entropy_heap.as_ref()
+        .map(|heap| heap.iter().map(|entry| (entry.entropy, entry.priority, entry.tiebreak, entry.idx)).collect()),
+      finalized: self.finalized.clone(),
+      forced: self.forced.clone(),
+      deferred_propagation: self.deferred_propagation.iter().copied().collect(),
+      rng: self.rng.state(),
+    };
+    serde_json::to_string(&state).expect("grid state should serialize")
+  }
+
+  // a grid continuing exactly where the saved one was, `step` makes the same choices it would
+  // have made as long as the loaded grid is configured like the saved one
+  pub fn load(json: &str, adjacency_rules: &Rc<AdjacencyData>, patterns: &Rc<P>) -> Result<Self, serde_json::Error> {
+    let state: GridState = serde_json::from_str(json)?;
+    let cell_count = state.width * state.height;
+    if state.pattern_count != patterns.len() || state.pattern_count > I::MAX_PATTERNS {
+      return Err(serde_json::Error::custom("saved grid doesn't match the given patterns"));
+    }
+    let pattern_count = state.pattern_count;
+    if !state.is_consistent(cell_count, pattern_count) {
+      return Err(serde_json::Error::custom("saved grid is inconsistent"));
+    }
+    let to_bitsets = |sets: Vec<Vec<usize>>| sets.into_iter().map(|set| pattern_set(pattern_count, set)).collect::<Vec<_>>();
+
+    let mut grid = Self::new_indexed(state.width, state.height, adjacency_rules, patterns, state.rng);
+    grid.cells = state.cells.into_iter().map(|c| c.map(I::from_pattern)).collect();
+    grid.options = to_bitsets(state.options);
+    grid.banned = state.banned.into_iter().map(|banned| banned.into_iter().collect()).collect();
+    grid.history = state.history.into_iter()
+      .map(|(choice, tiles, options, excluded)| HistoryEntry { choice, updated_tiles: tiles.into_iter().collect(), options: pattern_set(pattern_count, options), excluded })
+      .collect::<VecDeque<_>>();
+    grid.excluded = state.excluded;
+    grid.contradiction_counts = state.contradiction_counts;
+    grid.last_contradiction = state.last_contradiction;
+    grid.emptied_by = state.emptied_by.into_iter().collect();
+    grid.entropy_heap = state.entropy_heap.map(|entries| {
+      let entries: Vec<EntropyEntry> = entries.into_iter().map(|(entropy, priority, tiebreak, idx)| EntropyEntry { entropy, priority, tiebreak, idx }).collect();
+      // the entries are already a valid heap, so this keeps their order
+      BinaryHeap::from(entries)
+    });
+    grid.finalized = state.finalized;
+    grid.forced = state.forced;
+    grid.deferred_propagation = state.deferred_propagation.into_iter().collect();
+    grid.rng = SeededRng::from_state(state.rng);
+    Ok(grid)
+  }
+}
+
+impl GridState {
+  // every per-cell vector covers the grid and every cell and pattern index is in range, so
+  // nothing loaded can index out of bounds later
+  fn is_consistent(&self, cell_count: usize, pattern_count: usize) -> bool {
+    let cell = |idx: &usize| *idx < cell_count;
+    let pattern = |p: &usize| *p < pattern_count;
+    let exclusion = |(idx, p): &(usize, usize)| cell(idx) && pattern(p);
+    [self.cells.len(), self.options.len(), self.banned.len(), self.contradiction_counts.len(), self.finalized.len(), self.forced.len()]
+      .iter().all(|len| *len == cell_count)
+      && self.cells.iter().flatten().all(pattern)
+      && self.options.iter().chain(self.banned.iter()).flatten().all(pattern)
+      && self.history.iter().all(|(choice, tiles, options, excluded)| {
+        exclusion(&(choice.1, choice.0)) && tiles.iter().all(cell) && options.iter().all(pattern) && excluded.iter().all(exclusion)
+      })
+      && self.excluded.iter().all(exclusion)
+      && self.deferred_propagation.iter().all(cell)
+      && self.last_contradiction.iter().all(|(idx, _)| cell(idx))
+      && self.emptied_by.iter().all(|(idx, _)| cell(idx))
+      && self.entropy_heap.iter().flatten().all(|(_, _, _, idx)| cell(idx))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::rc::Rc;
+
+  use crate::{Grid, tests::{Ids, three_bands}};
+
+  #[test]
+  fn loaded_grid_continues_like_the_saved_one() {
+    let rules = three_bands();
+    let patterns = Rc::new(Ids(3));
+    for seed in 0..10 {
+      let mut straight: Grid<Ids> = Grid::new_with_seed(8, 8, &rules, &patterns, seed);
+      let mut saved: Grid<Ids> = Grid::new_with_seed(8, 8, &rules, &patterns, seed);
+      for _ in 0..10 {
+        let _ = saved.step();
+      }
+      let mut loaded: Grid<Ids> = Grid::load(&saved.save(), &rules, &patterns).unwrap();
+      assert_eq!(straight.run(), loaded.run());
+      assert_eq!(straight.to_indices(), loaded.to_indices());
+    }
+  }
+}
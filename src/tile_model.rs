@@ -1,19 +1,50 @@
-use std::collections::{HashMap};
+use std::{collections::HashMap, sync::atomic::AtomicUsize};
 
 use macroquad::prelude::*;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 
-use crate::{Drawable, WfcPreprocessor, TILE_SIZE, utils::{rotate_image, N_INDEXES}};
-
-const OPPOSITE_EDGES_INDEXES: [(usize, usize); 4] = [(0, 2), (1, 3), (2, 0), (3, 1)];
+use crate::{Drawable, WfcPreprocessor, PreprocessError, TILE_SIZE, utils::{rotate_image, reflect_image, Axis, report_progress, ProgressCallback}, neighbourhood::{Neighbourhood, Square4}};
 
+// every pattern holds one or more render variants, a cell picks among them by its position,
+// textures need a window so they only exist with the `render` feature
 #[derive(Clone)]
-pub struct TexturePattern(Vec<Texture2D>);
+pub struct TexturePattern {
+  #[cfg(feature = "render")]
+  textures: Vec<Vec<Texture2D>>,
+  images: Vec<Vec<Image>>,
+}
+
+impl TexturePattern {
+  // one pattern per group, holding the group's images as its variants
+  pub(crate) fn from_groups(images: &[Image], groups: &[Vec<usize>]) -> Self {
+    #[cfg(feature = "render")]
+    let textures: Vec<Vec<Texture2D>> = groups.iter()
+      .map(|g| g.iter().map(|idx| {
+        let tex = Texture2D::from_image(&images[*idx]);
+        tex.set_filter(FilterMode::Linear);
+        tex
+      }).collect())
+      .collect();
+    let variant_images = groups.iter().map(|g| g.iter().map(|idx| images[*idx].clone()).collect()).collect();
+    TexturePattern {
+      #[cfg(feature = "render")]
+      textures,
+      images: variant_images,
+    }
+  }
+}
+
+fn variant_index(x: u32, y: u32, variants: usize) -> usize {
+  (x as usize * 31 + y as usize * 17) % variants
+}
 
 impl Drawable for TexturePattern {
+  #[cfg(feature = "render")]
   fn draw(&self, x: f32, y: f32, idx: usize) {
+    let variants = &self.textures[idx];
     draw_texture_ex(
-      self
-      .0[idx], x, y,
+      variants[variant_index(x as u32, y as u32, variants.len())], x, y,
       WHITE,
       DrawTextureParams {
         dest_size: Some(Vec2::splat(TILE_SIZE)),
@@ -21,86 +52,270 @@ impl Drawable for TexturePattern {
       }
     );
   }
+  fn rasterize(&self, target: &mut Image, x: u32, y: u32, idx: usize) {
+    let variants = &self.images[idx];
+    let image = &variants[variant_index(x, y, variants.len())];
+    let size = TILE_SIZE as u32;
+    for ty in y..(y + size).min(target.height() as u32) {
+      for tx in x..(x + size).min(target.width() as u32) {
+        let sx = (tx - x) * image.width() as u32 / size;
+        let sy = (ty - y) * image.height() as u32 / size;
+        target.set_pixel(tx, ty, image.get_pixel(sx, sy));
+      }
+    }
+  }
   fn len(&self) -> usize {
-    self.0.len()
+    self.images.len()
   }
 }
 
+// how transparency in a sheet is treated, every mode but `Exact` drops fully transparent tiles
+// (e.g. the gaps some sheets are exported with) instead of extracting them as a pattern
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AlphaMode {
+  // alpha is compared like any other channel
+  Exact,
+  // alpha is compared like any other channel as with `Exact`, the only difference is that fully
+  // transparent tiles are dropped
+  DropEmpty,
+  // edges match on their colors alone
+  IgnoreAlpha,
+  // a fully transparent edge pixel matches anything on the other side
+  TransparentWildcard,
+}
+
 pub struct TileProcessor {
   rotate: bool,
-  tile_size: f32,
+  tile_width: f32,
+  tile_height: f32,
+  max_rotations_per_tile: usize,
+  edge_dedup: bool,
+  reflect: bool,
+  tolerance: u8,
+  tile_weights: Option<Vec<f32>>,
+  alpha_mode: AlphaMode,
+  progress: Option<(ProgressCallback, usize)>,
 }
 
 impl TileProcessor {
-  pub fn new(tile_size: f32, rotate: bool) -> Self {
+  // tiles don't have to be square, but a quarter turn wouldn't fit a non-square tile back into
+  // the grid, so those only get rotated by half turns
+  pub fn new(tile_width: f32, tile_height: f32, rotate: bool) -> Self {
     Self {
       rotate,
-      tile_size,
+      tile_width,
+      tile_height,
+      max_rotations_per_tile: 4,
+      edge_dedup: false,
+      reflect: false,
+      tolerance: 0,
+      tile_weights: None,
+      alpha_mode: AlphaMode::Exact,
+      progress: None,
     }
   }
+
+  // tiles with more distinct orientations than `max` contribute only their canonical one
+  // (the orientation with the smallest pixel data)
+  pub fn with_max_rotations_per_tile(mut self, max: usize) -> Self {
+    self.max_rotations_per_tile = max;
+    self
+  }
+
+  // tiles sharing all four edges become one pattern for the solver and are kept only as
+  // render variants of it
+  pub fn with_edge_dedup(mut self, edge_dedup: bool) -> Self {
+    self.edge_dedup = edge_dedup;
+    self
+  }
+
+  // tiles are also mirrored horizontally and vertically (every kept rotation of them with
+  // `rotate`), the mirrored copies go through the same dedup and edge matching as rotations but
+  // don't count towards `max_rotations_per_tile`
+  pub fn with_reflect(mut self, reflect: bool) -> Self {
+    self.reflect = reflect;
+    self
+  }
+
+  // edges match when every pixel is within `tolerance` of the other's in each channel, so slightly
+  // antialiased edges still connect, 0 requires identical edges
+  pub fn with_tolerance(mut self, tolerance: u8) -> Self {
+    self.tolerance = tolerance;
+    self
+  }
+
+  // a weight per tile of the sheet, in the order they are read (column by column), tiles past the
+  // end get 1. Every rotated or mirrored copy inherits its tile's weight, a copy identical to one
+  // from another tile adds its weight to it and edge dedup sums the weights of merged variants.
+  // Tiles with weight 0 are left out entirely. Use `process_weighted` to get the pattern weights
+  pub fn with_tile_weights(mut self, tile_weights: Vec<f32>) -> Self {
+    self.tile_weights = Some(tile_weights);
+    self
+  }
+
+  pub fn with_alpha_mode(mut self, alpha_mode: AlphaMode) -> Self {
+    self.alpha_mode = alpha_mode;
+    self
+  }
+
+  // `callback` gets `(done, total)` every `every` tiles while adjacency rules are computed
+  pub fn with_progress(mut self, callback: ProgressCallback, every: usize) -> Self {
+    self.progress = Some((callback, every.max(1)));
+    self
+  }
+
+  fn group_images(&self, images: &[Image]) -> Vec<Vec<usize>> {
+    if !self.edge_dedup {
+      return (0..images.len()).map(|idx| vec![idx]).collect();
+    }
+    let mut groups: Vec<Vec<usize>> = vec![];
+    let mut group_edges: Vec<[usize; 4]> = vec![];
+    for (idx, edges) in get_edges_for_images(images, self.tolerance, self.alpha_mode).into_iter().enumerate() {
+      if let Some(group_idx) = group_edges.iter().position(|e| *e == edges) {
+        groups[group_idx].push(idx);
+      } else {
+        group_edges.push(edges);
+        groups.push(vec![idx]);
+      }
+    }
+    groups
+  }
 }
 
 impl WfcPreprocessor for TileProcessor {
   type Pattern = TexturePattern;
 
   fn create_patterns(&self, images: &[Image]) -> Self::Pattern {
-    let patterns: Vec<Texture2D> = images.iter()
-      .map(|i| {
-        let tex = Texture2D::from_image(i);
-        tex.set_filter(FilterMode::Linear);
-        tex
-      })
-      .collect();
-      TexturePattern(patterns)
+    TexturePattern::from_groups(images, &self.group_images(images))
   }
 
   fn create_adjacency_rules(&self, images: &[Image]) -> crate::utils::AdjacencyData {
-    let edge_data = get_edges_for_images(images);
-    let mut adjacenncy_data = vec![];
+    let representatives: Vec<Image>;
+    let images = if self.edge_dedup {
+      representatives = self.group_images(images).iter().map(|g| images[g[0]].clone()).collect();
+      &representatives[..]
+    } else {
+      images
+    };
+    let edge_data = get_edges_for_images(images, self.tolerance, self.alpha_mode);
+    // wildcards make matching non-transitive, so edges are then compared pixel by pixel
+    let edge_pixels: Vec<[Vec<[u8; 4]>; 4]> = if self.alpha_mode == AlphaMode::TransparentWildcard {
+      images.iter().map(|img| get_edge_colors(img).map(|e| edge_bytes(&e, self.alpha_mode))).collect()
+    } else {
+      vec![]
+    };
+    let connects = |source: usize, target: usize, side: usize, target_side: usize| {
+      if self.alpha_mode == AlphaMode::TransparentWildcard {
+        edges_connect(&edge_pixels[source][side], &edge_pixels[target][target_side], self.tolerance)
+      } else {
+        edge_data[source][side] == edge_data[target][target_side]
+      }
+    };
+    let done = AtomicUsize::new(0);
+    // edges are the four sides of a tile, in `Square4` direction order
+    let neighbourhood = Square4;
 
-    for (idx, _) in images.iter().enumerate() {
-      let source_edge_connections = edge_data[idx];
+    #[cfg(feature = "parallel")]
+    let indexes = (0..images.len()).into_par_iter();
+    #[cfg(not(feature = "parallel"))]
+    let indexes = 0..images.len();
+    indexes.map(|idx| {
       let mut adjacencies : HashMap<(isize, isize), Vec<usize>> = HashMap::new();
-      for (nx, ny) in N_INDEXES {
-        adjacencies.insert((nx, ny), vec![]);
+      for offset in neighbourhood.offsets() {
+        adjacencies.insert(*offset, vec![]);
       }
 
       for (target_idx, _) in images.iter().enumerate() {
-        let target_edge_connections = edge_data[target_idx];
-
-        for (n_idx, (src_edge, target_edge)) in OPPOSITE_EDGES_INDEXES.iter().enumerate() {
-          if source_edge_connections[*src_edge] == target_edge_connections[*target_edge] {
-            let (nx, ny) = N_INDEXES[n_idx];
-            adjacencies.get_mut(&(nx, ny)).unwrap().push(target_idx);
+        for (direction, offset) in neighbourhood.offsets().iter().enumerate() {
+          if connects(idx, target_idx, direction, neighbourhood.opposite(direction)) {
+            adjacencies.get_mut(offset).unwrap().push(target_idx);
           }
         }
       }
-      adjacenncy_data.push(adjacencies);
-    }
+      report_progress(&self.progress, &done, images.len());
+      adjacencies
+    }).collect()
+  }
+
+  // the first variant of every edge dedup group
+  fn pattern_images(&self, images: &[Image]) -> Vec<Image> {
+    self.group_images(images).iter().map(|g| images[g[0]].clone()).collect()
+  }
 
-    adjacenncy_data
+  // variants merged by edge dedup add up their weights
+  fn pattern_weights(&self, images: &[Image], image_weights: Vec<f32>) -> Vec<f32> {
+    self.group_images(images).iter().map(|g| g.iter().map(|idx| image_weights[*idx]).sum()).collect()
   }
 
-  fn extract_images(&self, image: &Image) -> Vec<Image> {
+  fn config_key(&self) -> String {
+    format!("tile size={}x{} rotate={} reflect={} max_rotations={} edge_dedup={} tolerance={} weights={:?} alpha={:?}", self.tile_width, self.tile_height, self.rotate, self.reflect, self.max_rotations_per_tile, self.edge_dedup, self.tolerance, self.tile_weights, self.alpha_mode)
+  }
+
+  fn extract_images(&self, image: &Image) -> Result<Vec<Image>, PreprocessError> {
+    Ok(self.extract_weighted_images(image)?.0)
+  }
+
+  fn extract_weighted_images(&self, image: &Image) -> Result<(Vec<Image>, Vec<f32>), PreprocessError> {
     let mut images: Vec<Image> = vec![];
+    let mut weights: Vec<f32> = vec![];
+    let mut seen: HashMap<Vec<u8>, usize> = HashMap::new();
+    let (tile_width, tile_height) = (self.tile_width as u16, self.tile_height as u16);
+    let rotations: &[usize] = if tile_width == tile_height { &[1, 2, 3] } else { &[2] };
+    let mut tile_idx = 0;
 
-    for x in (0..image.width).step_by(self.tile_size as usize) {
-      for y in (0..image.height).step_by(self.tile_size as usize) {
-        let img = image.sub_image(Rect::new(x as f32, y as f32, self.tile_size, self.tile_size));
+    // partial tiles at the right/bottom edges of a sheet that isn't an exact multiple of the
+    // tile size are skipped, only fully-contained tiles get extracted
+    for x in (0..image.width / tile_width).map(|tx| tx * tile_width) {
+      for y in (0..image.height / tile_height).map(|ty| ty * tile_height) {
+        let weight = self.tile_weights.as_ref().and_then(|w| w.get(tile_idx)).copied().unwrap_or(1.);
+        tile_idx += 1;
+        if weight == 0. {
+          continue;
+        }
+        let img = image.sub_image(Rect::new(x as f32, y as f32, self.tile_width, self.tile_height));
+        if self.alpha_mode != AlphaMode::Exact && img.bytes.chunks(4).all(|pixel| pixel[3] == 0) {
+          continue;
+        }
+        let mut orientations: Vec<Image> = vec![];
         if self.rotate {
-          for rot in 1..4 {
+          for rot in rotations.iter().copied() {
             let rotated_image = rotate_image(&img, rot);
-            if images.iter().all(|p| p.get_image_data() != rotated_image.get_image_data()) {
-              images.push(rotated_image);
+            debug_assert!(edges_follow_rotation(&img, &rotated_image, rot), "edges of tile at {}, {} don't follow its rotation by {}", x, y, rot);
+            if orientations.iter().all(|p| p.get_image_data() != rotated_image.get_image_data()) {
+              orientations.push(rotated_image);
+            }
+          }
+        }
+        if orientations.iter().all(|p| p.get_image_data() != img.get_image_data()) {
+          orientations.push(img);
+        }
+        if orientations.len() > self.max_rotations_per_tile {
+          let canonical = orientations.into_iter().min_by(|a, b| a.get_image_data().cmp(b.get_image_data())).unwrap();
+          orientations = vec![canonical];
+        }
+        if self.reflect {
+          for base in orientations.clone() {
+            for axis in [Axis::Vertical, Axis::Horizontal] {
+              let reflected_image = reflect_image(&base, axis);
+              debug_assert!(edges_follow_reflection(&base, &reflected_image, axis), "edges of tile at {}, {} don't follow its reflection", x, y);
+              if orientations.iter().all(|p| p.get_image_data() != reflected_image.get_image_data()) {
+                orientations.push(reflected_image);
+              }
             }
           }
         }
-        if images.iter().all(|p| p.get_image_data() != img.get_image_data()) {
-          images.push(img);
+        for img in orientations {
+          if let Some(existing) = seen.get(&img.bytes) {
+            weights[*existing] += weight;
+          } else {
+            seen.insert(img.bytes.clone(), images.len());
+            images.push(img);
+            weights.push(weight);
+          }
         }
       }
     }
-    images
+    Ok((images, weights))
   }
 }
 
@@ -127,30 +342,321 @@ fn get_edge_colors(img: &Image) -> [Vec<Color>; 4] {
   [top, right, bottom, left]
 }
 
-#[derive(Hash, PartialEq, Eq)]
-struct VecU8(Vec<[u8; 4]>);
+// edges read clockwise around the tile (bottom and left reversed), so rotating a tile by `rot`
+// quarter turns clockwise just shifts its edges by `rot`
+fn clockwise_edge_colors(img: &Image) -> [Vec<Color>; 4] {
+  let [top, right, mut bottom, mut left] = get_edge_colors(img);
+  bottom.reverse();
+  left.reverse();
+  [top, right, bottom, left]
+}
+
+fn edges_follow_rotation(base: &Image, rotated: &Image, rot: usize) -> bool {
+  let base_edges = clockwise_edge_colors(base);
+  let rotated_edges = clockwise_edge_colors(rotated);
+  (0..4).all(|side| rotated_edges[(side + rot) % 4] == base_edges[side])
+}
+
+// a mirrored tile swaps the edges across the mirror axis and reverses the ones along it
+fn edges_follow_reflection(base: &Image, reflected: &Image, axis: Axis) -> bool {
+  let [top, right, bottom, left] = get_edge_colors(base);
+  let reversed = |mut edge: Vec<Color>| {
+    edge.reverse();
+    edge
+  };
+  let expected = match axis {
+    Axis::Vertical => [reversed(top), left, reversed(bottom), right],
+    Axis::Horizontal => [bottom, reversed(right), top, reversed(left)],
+  };
+  get_edge_colors(reflected) == expected
+}
 
-fn get_edges_for_images(images: &[Image]) -> Vec<[usize; 4]> {
-  let mut idx = 0;
-  let mut edges_store : HashMap<VecU8, usize> = HashMap::new();
-  let mut edges: Vec<[usize; 4]> = vec![];
+fn edge_bytes(edge: &[Color], alpha_mode: AlphaMode) -> Vec<[u8; 4]> {
+  edge.iter().map(|c| {
+    let mut bytes = color_to_slice(c);
+    if alpha_mode == AlphaMode::IgnoreAlpha {
+      bytes[3] = 255;
+    }
+    bytes
+  }).collect()
+}
 
-  for img in images {
-    let mut edge_indexes = vec![None; 4];
+// `edges_within_tolerance` where a fully transparent pixel on either side matches anything
+fn edges_connect(a: &[[u8; 4]], b: &[[u8; 4]], tolerance: u8) -> bool {
+  a.len() == b.len() && a.iter().zip(b).all(|(pa, pb)| {
+    pa[3] == 0 || pb[3] == 0 || pa.iter().zip(pb).all(|(ca, cb)| ca.abs_diff(*cb) <= tolerance)
+  })
+}
 
-    for (side_idx, e) in get_edge_colors(img).iter().enumerate() {
-      let c_bytes = VecU8(e.into_iter().map(|c| color_to_slice(c)).collect());
-      if let Some(i) = edges_store.get(&c_bytes) {
-        edge_indexes[side_idx] = Some(i.clone());
-      } else {
-        edge_indexes[side_idx] = Some(idx);
-        edges_store.insert(c_bytes, idx);
-        idx += 1;
+fn edges_within_tolerance(a: &[[u8; 4]], b: &[[u8; 4]], tolerance: u8) -> bool {
+  a.len() == b.len() && a.iter().zip(b).all(|(pa, pb)| pa.iter().zip(pb).all(|(ca, cb)| ca.abs_diff(*cb) <= tolerance))
+}
+
+// edges with the same index match. Without a tolerance that's exact equality, with one the
+// distinct edges are clustered in byte order: each joins the first cluster whose representative
+// (its smallest edge) is within the tolerance or starts one. Edges are only ever compared against
+// those fixed representatives, so the clusters don't depend on the order of `images`
+fn get_edges_for_images(images: &[Image], tolerance: u8, alpha_mode: AlphaMode) -> Vec<[usize; 4]> {
+  let image_edges: Vec<[Vec<[u8; 4]>; 4]> = images.iter().map(|img| get_edge_colors(img).map(|e| edge_bytes(&e, alpha_mode))).collect();
+  let mut distinct: Vec<&Vec<[u8; 4]>> = image_edges.iter().flatten().collect();
+  distinct.sort_unstable();
+  distinct.dedup();
+
+  let mut representatives: Vec<&Vec<[u8; 4]>> = vec![];
+  let mut cluster_of: HashMap<&Vec<[u8; 4]>, usize> = HashMap::new();
+  for edge in distinct {
+    let matched = if tolerance == 0 {
+      None
+    } else {
+      representatives.iter().position(|r| edges_within_tolerance(r, edge, tolerance))
+    };
+    let cluster = matched.unwrap_or_else(|| {
+      representatives.push(edge);
+      representatives.len() - 1
+    });
+    cluster_of.insert(edge, cluster);
+  }
+
+  image_edges.iter().map(|edges| edges.each_ref().map(|e| cluster_of[e])).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rasterizes_a_tile_scaled_to_the_cell() {
+    let mut tile = Image::gen_image_color(2, 2, RED);
+    tile.set_pixel(1, 1, BLUE);
+    // built by hand, `create_patterns` would make textures with `render` and those need a window
+    let patterns = TexturePattern {
+      #[cfg(feature = "render")]
+      textures: vec![],
+      images: vec![vec![tile.clone()]],
+    };
+    let mut target = Image::gen_image_color(40, 40, WHITE);
+    patterns.rasterize(&mut target, 10, 10, 0);
+    let half = TILE_SIZE as u32 / 2;
+    assert_eq!(target.get_pixel(10, 10), tile.get_pixel(0, 0));
+    assert_eq!(target.get_pixel(9 + half, 9 + half), tile.get_pixel(0, 0));
+    assert_eq!(target.get_pixel(10 + half, 10 + half), tile.get_pixel(1, 1));
+    assert_eq!(target.get_pixel(9 + 2 * half, 9 + 2 * half), tile.get_pixel(1, 1));
+    assert_eq!(target.get_pixel(9, 9), Image::gen_image_color(1, 1, WHITE).get_pixel(0, 0));
+  }
+
+  // a 2x2 tile with one blue corner, every quarter turn of it is different
+  fn corner_tile() -> Image {
+    let mut tile = Image::gen_image_color(2, 2, RED);
+    tile.set_pixel(0, 0, BLUE);
+    tile
+  }
+
+  #[test]
+  fn limits_rotations_per_tile() {
+    let sheet = corner_tile();
+    assert_eq!(TileProcessor::new(2., 2., true).extract_images(&sheet).unwrap().len(), 4);
+    let canonical = TileProcessor::new(2., 2., true).with_max_rotations_per_tile(3).extract_images(&sheet).unwrap();
+    assert_eq!(canonical.len(), 1);
+    let smallest = (0..4).map(|rot| rotate_image(&sheet, rot)).min_by(|a, b| a.get_image_data().cmp(b.get_image_data())).unwrap();
+    assert_eq!(canonical[0].get_image_data(), smallest.get_image_data());
+    // a tile that looks the same turned has a single orientation to begin with
+    assert_eq!(TileProcessor::new(2., 2., true).with_max_rotations_per_tile(1).extract_images(&Image::gen_image_color(2, 2, RED)).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn edge_dedup_merges_tiles_differing_inside() {
+    let plain = Image::gen_image_color(3, 3, RED);
+    let mut dotted = plain.clone();
+    dotted.set_pixel(1, 1, BLUE);
+    let mut edged = plain.clone();
+    edged.set_pixel(1, 0, BLUE);
+    let images = [plain, dotted, edged];
+    let processor = TileProcessor::new(3., 3., false).with_edge_dedup(true);
+    assert_eq!(processor.group_images(&images), vec![vec![0, 1], vec![2]]);
+    assert_eq!(processor.create_adjacency_rules(&images).len(), 2);
+    assert_eq!(processor.pattern_weights(&images, vec![1., 2., 4.]), vec![3., 4.]);
+    assert_eq!(TileProcessor::new(3., 3., false).create_adjacency_rules(&images).len(), 3);
+  }
+
+  #[test]
+  fn skips_partial_tiles() {
+    let mut sheet = Image::gen_image_color(48, 32, RED);
+    // only in the partial tile
+    sheet.set_pixel(40, 10, BLUE);
+    let tiles = TileProcessor::new(32., 32., false).extract_images(&sheet).unwrap();
+    assert_eq!(tiles.len(), 1);
+    assert_eq!((tiles[0].width, tiles[0].height), (32, 32));
+    assert!(tiles[0].get_image_data().iter().all(|pixel| *pixel == sheet.get_image_data()[0]));
+  }
+
+  #[test]
+  fn reflect_adds_mirrored_tiles() {
+    let tiles = TileProcessor::new(2., 2., false).with_reflect(true).extract_images(&corner_tile()).unwrap();
+    // the blue corner in three of the four corners, a diagonal mirror would need a rotation too
+    assert_eq!(tiles.len(), 3);
+    let blue = corner_tile().get_pixel(0, 0);
+    let corners: Vec<(u32, u32)> = tiles.iter().map(|tile| [(0, 0), (1, 0), (0, 1), (1, 1)].into_iter().find(|(x, y)| tile.get_pixel(*x, *y) == blue).unwrap()).collect();
+    assert_eq!(corners, vec![(0, 0), (1, 0), (0, 1)]);
+  }
+
+  #[test]
+  fn slices_non_square_tiles() {
+    // 3 x 2 tiles of 32x48, each marked differently, plus leftover pixels
+    let mut sheet = Image::gen_image_color(100, 110, WHITE);
+    for tile in 0..6u32 {
+      sheet.set_pixel((tile % 3) * 32 + 1, (tile / 3) * 48 + 2 + tile, RED);
+    }
+    let tiles = TileProcessor::new(32., 48., false).extract_images(&sheet).unwrap();
+    assert_eq!(tiles.len(), 6);
+    assert!(tiles.iter().all(|tile| (tile.width, tile.height) == (32, 48)));
+    // only half turns keep the shape
+    let turned = TileProcessor::new(32., 48., true).extract_images(&sheet).unwrap();
+    assert_eq!(turned.len(), 12);
+    assert!(turned.iter().all(|tile| (tile.width, tile.height) == (32, 48)));
+  }
+
+  #[test]
+  fn non_square_edges_connect() {
+    // 2x3 tiles, red down the right side of the first and the left side of the second
+    let mut sheet = Image::gen_image_color(4, 3, WHITE);
+    for y in 0..3 {
+      sheet.set_pixel(1, y, RED);
+      sheet.set_pixel(2, y, RED);
+    }
+    let processor = TileProcessor::new(2., 3., false);
+    let tiles = processor.extract_images(&sheet).unwrap();
+    let rules = processor.create_adjacency_rules(&tiles);
+    assert_eq!(rules[0][&(1, 0)], vec![1]);
+    assert_eq!(rules[1][&(-1, 0)], vec![0]);
+    assert_eq!(rules[0][&(0, 1)], vec![0]);
+  }
+
+  #[test]
+  fn rotated_edges_land_on_the_right_sides() {
+    // every pixel a different colour, so all four edges differ and none reads the same reversed
+    let mut tile = Image::gen_image_color(3, 3, WHITE);
+    for x in 0..3 {
+      for y in 0..3 {
+        tile.set_pixel(x, y, Color::from_rgba(x as u8 * 100, y as u8 * 100, 50, 255));
       }
     }
+    let [top, right, bottom, left] = get_edge_colors(&tile);
+    assert!(top != right && right != bottom && bottom != left && left != top);
+    for rot in 0..4 {
+      let rotated = rotate_image(&tile, rot);
+      assert!(edges_follow_rotation(&tile, &rotated, rot));
+      assert!(!edges_follow_rotation(&tile, &rotated, (rot + 1) % 4));
+    }
+    // a quarter turn clockwise moves the top edge to the right and the left edge, read upwards,
+    // to the top
+    let [turned_top, turned_right, ..] = get_edge_colors(&rotate_image(&tile, 1));
+    assert_eq!(turned_right, top);
+    assert_eq!(turned_top, left.into_iter().rev().collect::<Vec<_>>());
+  }
 
-    edges.push([edge_indexes[0].unwrap(), edge_indexes[1].unwrap(), edge_indexes[2].unwrap(), edge_indexes[3].unwrap()]);
+  // a 2x2 tile of a single grey
+  fn grey_tile(value: u8) -> Image {
+    Image::gen_image_color(2, 2, Color::from_rgba(value, value, value, 255))
   }
 
-  edges
+  #[test]
+  fn matches_edges_within_the_tolerance() {
+    let grey = |value: u8| vec![[value, value, value, 255]; 2];
+    assert!(edges_within_tolerance(&grey(100), &grey(104), 4));
+    assert!(!edges_within_tolerance(&grey(100), &grey(105), 4));
+    assert!(!edges_within_tolerance(&grey(100), &grey(101), 0));
+
+    let tiles = [grey_tile(100), grey_tile(105), grey_tile(110)];
+    let [a, b, c] = tiles.each_ref().map(|tile| get_edge_colors(tile).map(|e| edge_bytes(&e, AlphaMode::Exact))[0][0][0]);
+    let tolerance = (b - a).max(c - b);
+    assert!(c - a > tolerance);
+    // a matches b and b matches c but a doesn't match c, so b joins a's cluster and c starts its
+    // own, whichever order the tiles come in
+    for order in [[0, 1, 2], [1, 0, 2], [2, 1, 0], [1, 2, 0]] {
+      let images: Vec<Image> = order.iter().map(|idx| tiles[*idx].clone()).collect();
+      let edges = get_edges_for_images(&images, tolerance, AlphaMode::Exact);
+      let edge_of = |tile: usize| edges[order.iter().position(|idx| *idx == tile).unwrap()];
+      assert!(edge_of(0) == edge_of(1) && edge_of(1) != edge_of(2), "{:?}", order);
+    }
+    // without a tolerance every grey is its own edge
+    let exact = get_edges_for_images(&tiles, 0, AlphaMode::Exact);
+    assert!(exact[0] != exact[1] && exact[1] != exact[2]);
+  }
+
+  // the tiles stacked into a one tile wide sheet, in the order they are read
+  fn column_sheet(tiles: &[Image]) -> Image {
+    let (width, height) = (tiles[0].width, tiles[0].height);
+    let mut sheet = Image::gen_image_color(width, height * tiles.len() as u16, WHITE);
+    for (idx, tile) in tiles.iter().enumerate() {
+      for x in 0..width as u32 {
+        for y in 0..height as u32 {
+          sheet.set_pixel(x, y + idx as u32 * height as u32, tile.get_pixel(x, y));
+        }
+      }
+    }
+    sheet
+  }
+
+  #[test]
+  fn tile_weights_follow_their_copies() {
+    // every quarter turn of the corner tile gets its weight
+    let processor = TileProcessor::new(2., 2., true).with_tile_weights(vec![2.]);
+    let (images, weights) = processor.extract_weighted_images(&corner_tile()).unwrap();
+    assert_eq!((images.len(), weights), (4, vec![2.; 4]));
+
+    // red tiles with a different centre share all four edges
+    let centred = |color| {
+      let mut tile = Image::gen_image_color(3, 3, RED);
+      tile.set_pixel(1, 1, color);
+      tile
+    };
+    let sheet = column_sheet(&[centred(BLUE), centred(GREEN), Image::gen_image_color(3, 3, BLUE), centred(BLUE)]);
+    // the blue tile is dropped and the last tile, past the given weights, counts 1 towards its
+    // duplicate
+    let processor = TileProcessor::new(3., 3., false).with_tile_weights(vec![2., 3., 0.]);
+    let (images, weights) = processor.extract_weighted_images(&sheet).unwrap();
+    assert_eq!((images.len(), weights.clone()), (2, vec![3., 3.]));
+    // edge dedup merges both into one pattern weighing as much as they do together
+    let deduped = TileProcessor::new(3., 3., false).with_tile_weights(vec![2., 3., 0.]).with_edge_dedup(true);
+    assert_eq!(deduped.pattern_weights(&images, weights), vec![6.]);
+  }
+
+  #[test]
+  fn alpha_modes() {
+    let mut wildcard = Image::gen_image_color(2, 2, BLUE);
+    wildcard.set_pixel(1, 0, Color::new(0., 0., 0., 0.));
+    wildcard.set_pixel(1, 1, Color::new(0., 0., 0., 0.));
+    let sheet = column_sheet(&[
+      Image::gen_image_color(2, 2, Color::new(1., 0., 0., 1.)),
+      Image::gen_image_color(2, 2, Color::new(1., 0., 0., 0.5)),
+      Image::gen_image_color(2, 2, Color::new(0., 0., 0., 0.)),
+      wildcard,
+    ]);
+    let right = (1, 0);
+    let rules = |alpha_mode| {
+      let processor = TileProcessor::new(2., 2., false).with_alpha_mode(alpha_mode);
+      let images = processor.extract_images(&sheet).unwrap();
+      (images.len(), processor.create_adjacency_rules(&images))
+    };
+
+    // the transparent tile stays and only connects to itself, opaque and translucent red don't
+    // connect
+    let (count, exact) = rules(AlphaMode::Exact);
+    assert_eq!(count, 4);
+    assert_eq!(exact[2][&right], vec![2]);
+    assert_eq!(exact[0][&right], vec![0]);
+    // from here on the transparent tile is dropped, the wildcard tile is 2
+    let (count, drop_empty) = rules(AlphaMode::DropEmpty);
+    assert_eq!(count, 3);
+    assert_eq!(drop_empty[0][&right], vec![0]);
+    let (count, ignore_alpha) = rules(AlphaMode::IgnoreAlpha);
+    assert_eq!(count, 3);
+    assert_eq!(ignore_alpha[0][&right], vec![0, 1]);
+    // the wildcard tile's transparent right edge takes any tile, its blue left edge still doesn't
+    // match red
+    let (count, wildcard) = rules(AlphaMode::TransparentWildcard);
+    assert_eq!(count, 3);
+    assert_eq!(wildcard[2][&right], vec![0, 1, 2]);
+    assert_eq!(wildcard[0][&right], vec![0]);
+  }
 }
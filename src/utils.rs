@@ -1,12 +1,63 @@
-use std::collections::HashMap;
+use std::{cell::Cell, collections::HashMap, sync::{Arc, atomic::{AtomicUsize, Ordering}}};
 
+use fixedbitset::FixedBitSet;
 use macroquad::prelude::*;
 
+use crate::{RulesError, neighbourhood::{Neighbourhood, Square4}};
+#[cfg(feature = "render")]
 use crate::TILE_SIZE;
 
 pub const N_INDEXES: [(isize, isize); 4] = [(0, -1), (1, 0), (0, 1), (-1, 0)];
+// `N_INDEXES` plus the diagonals, clockwise from up
+pub const N_INDEXES_DIAGONAL: [(isize, isize); 8] = [(0, -1), (1, -1), (1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1)];
+// axial offsets of the six pointy-top hex neighbours, counter-clockwise from east
+pub const HEX_INDEXES: [(isize, isize); 6] = [(1, 0), (1, -1), (0, -1), (-1, 0), (-1, 1), (0, 1)];
 
 pub type AdjacencyData = Vec<HashMap<(isize, isize), Vec<usize>>>;
+// `AdjacencyData` with every neighbour list as a bitset
+pub type AdjacencyBits = Vec<HashMap<(isize, isize), FixedBitSet>>;
+
+// integer type a grid stores collapsed cells' pattern ids as, a narrower one shrinks the grid.
+// Pattern ids are `usize` everywhere else and only converted when stored or read back
+pub trait PatternIndex: Copy + PartialEq + std::fmt::Debug + 'static {
+  // the largest pattern count the type can hold ids for
+  const MAX_PATTERNS: usize;
+  fn from_pattern(pattern: usize) -> Self;
+  fn pattern(self) -> usize;
+}
+
+macro_rules! impl_pattern_index {
+  ($($t:ty),*) => {
+    $(impl PatternIndex for $t {
+      const MAX_PATTERNS: usize = (<$t>::MAX as usize).saturating_add(1);
+
+      fn from_pattern(pattern: usize) -> Self {
+        pattern as $t
+      }
+
+      fn pattern(self) -> usize {
+        self as usize
+      }
+    })*
+  };
+}
+
+impl_pattern_index!(u8, u16, u32);
+
+impl PatternIndex for usize {
+  const MAX_PATTERNS: usize = usize::MAX;
+
+  fn from_pattern(pattern: usize) -> Self {
+    pattern
+  }
+
+  fn pattern(self) -> usize {
+    self
+  }
+}
+
+// receives `(done, total)` while adjacency rules are computed, called from worker threads
+pub type ProgressCallback = Arc<dyn Fn(usize, usize) + Send + Sync>;
 
 pub fn xy_from_index(index: usize, width: usize) -> (usize, usize) {
   (index % width, index / width)
@@ -16,10 +67,100 @@ pub fn index_from_xy(x: usize, y: usize, width: usize) -> usize {
   x + y * width
 }
 
-// fn color_to_slice(c: &Color) -> [u8; 4] {
-//   [(c.r * 255.) as u8, (c.g * 255.) as u8, (c.b * 255.) as u8, (c.a * 255.) as u8]
-// }
+// axial `q`, `r` of a cell on a hex grid stored row by row with odd rows shifted half a hex to
+// the right, `r` is the row and `q` the column minus half the row
+pub fn axial_from_index(index: usize, width: usize) -> (isize, isize) {
+  let (x, y) = xy_from_index(index, width);
+  (x as isize - (y / 2) as isize, y as isize)
+}
+
+// inverse of `axial_from_index`, `None` outside the `width` x `height` grid
+pub fn index_from_axial(q: isize, r: isize, width: usize, height: usize) -> Option<usize> {
+  let x = usize::try_from(q + r.div_euclid(2)).ok().filter(|x| *x < width)?;
+  let y = usize::try_from(r).ok().filter(|y| *y < height)?;
+  Some(index_from_xy(x, y, width))
+}
+
+// index of the cell `dx`, `dy` away from `idx` on a `width` x `height` grid, `None` off the grid
+pub fn checked_neighbour(idx: usize, dx: isize, dy: isize, width: usize, height: usize) -> Option<usize> {
+  let (x, y) = xy_from_index(idx, width);
+  let nx = x.checked_add_signed(dx).filter(|nx| *nx < width)?;
+  let ny = y.checked_add_signed(dy).filter(|ny| *ny < height)?;
+  Some(index_from_xy(nx, ny, width))
+}
+
+// xorshift64* generator independent of macroquad's global state, the same seed always yields
+// the same sequence. The state sits in a `Cell` so read-only code paths can roll too
+pub struct SeededRng(Cell<u64>);
+
+impl SeededRng {
+  pub fn new(seed: u64) -> Self {
+    // splitmix64 scrambles the seed, so nearby seeds don't start out correlated and 0 is usable
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    Self(Cell::new((z ^ (z >> 31)).max(1)))
+  }
+
+  // the raw generator state, `from_state` continues the sequence exactly where it was
+  pub fn state(&self) -> u64 {
+    self.0.get()
+  }
+
+  pub fn from_state(state: u64) -> Self {
+    Self(Cell::new(state.max(1)))
+  }
+
+  pub fn next_u64(&self) -> u64 {
+    let mut x = self.0.get();
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    self.0.set(x);
+    x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+  }
+
+  pub fn gen_range(&self, low: f64, high: f64) -> f64 {
+    low + (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64 * (high - low)
+  }
+
+  pub fn choose<'a, T>(&self, items: &'a [T]) -> Option<&'a T> {
+    if items.is_empty() {
+      return None;
+    }
+    items.get((self.next_u64() % items.len() as u64) as usize)
+  }
+}
+
+// picks an option with probability proportional to its weight, options without a positive
+// weight are never picked so there's nothing to pick when none has one
+pub fn weighted_choice(rng: &SeededRng, options: &[usize], weight: impl Fn(usize) -> f64) -> Option<usize> {
+  let total: f64 = options.iter().map(|p| weight(*p)).sum();
+  if total <= 0. {
+    return None;
+  }
+  let mut roll = rng.gen_range(0., total);
+  for p in options {
+    let w = weight(*p);
+    if roll < w {
+      return Some(*p);
+    }
+    roll -= w;
+  }
+  options.iter().rev().find(|p| weight(**p) > 0.).copied()
+}
+
+// counts one more finished item and reports it every `every` items and once more on reaching `total`
+pub fn report_progress(progress: &Option<(ProgressCallback, usize)>, done: &AtomicUsize, total: usize) {
+  if let Some((callback, every)) = progress {
+    let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+    if done.is_multiple_of(*every) || done == total {
+      callback(done, total);
+    }
+  }
+}
 
+// `rot` quarter turns clockwise, every turn swaps the width and height
 pub fn rotate_image(image: &Image, rot: usize) -> Image {
   let mut new_image = image.clone();
   if rot == 0 {
@@ -27,12 +168,12 @@ pub fn rotate_image(image: &Image, rot: usize) -> Image {
   }
 
   for _ in 0..rot {
-    let image_store = new_image.clone();
-    for x in 0..new_image.width() {
-      for y in 0..new_image.height() {
+    let image_store = new_image;
+    new_image = Image::gen_image_color(image_store.height, image_store.width, BLANK);
+    for x in 0..image_store.width() {
+      for y in 0..image_store.height() {
         let x = x as u32;
         let y = y as u32;
-        // new_image.set_pixel(y, image.width() as u32 - 1 - x, image.get_pixel(x, y)); //Anti-CW
         new_image.set_pixel(image_store.height() as u32 - 1 - y, x, image_store.get_pixel(x, y)); //CW
       }
     }
@@ -40,13 +181,36 @@ pub fn rotate_image(image: &Image, rot: usize) -> Image {
   new_image
 }
 
+// the line an image is mirrored across, `Vertical` swaps left and right, `Horizontal` top and bottom
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Axis {
+  Horizontal,
+  Vertical,
+}
+
+pub fn reflect_image(image: &Image, axis: Axis) -> Image {
+  let mut new_image = image.clone();
+  let (width, height) = (image.width() as u32, image.height() as u32);
+  for x in 0..width {
+    for y in 0..height {
+      let (tx, ty) = match axis {
+        Axis::Vertical => (width - 1 - x, y),
+        Axis::Horizontal => (x, height - 1 - y),
+      };
+      new_image.set_pixel(tx, ty, image.get_pixel(x, y));
+    }
+  }
+  new_image
+}
+
+#[cfg(feature = "render")]
 pub fn draw_patterns(patterns: &[Image], y_offset: f32, text: &str) {
   let start_x = 100. + 10. * TILE_SIZE;
   let mut x = start_x;
   let mut y = y_offset;
   draw_text(text, x - 10., y - 50., 30., WHITE);
   for (idx, img) in patterns.iter().enumerate() {
-    let tex = Texture2D::from_image(&img);
+    let tex = Texture2D::from_image(img);
     draw_rectangle_lines(x - 10., y - 10., TILE_SIZE + 20., TILE_SIZE + 20., 5., YELLOW);
     draw_texture_ex(
       tex, x, y, WHITE,
@@ -63,3 +227,459 @@ pub fn draw_patterns(patterns: &[Image], y_offset: f32, text: &str) {
     }
   }
 }
+
+// `draw_patterns` of the inspected patterns with `selected` enlarged on the left, listing the
+// patterns allowed next to it in every direction
+#[cfg(feature = "render")]
+pub fn draw_pattern_inspector(patterns: &[(usize, Image)], rules: &AdjacencyData, selected: usize) {
+  let images: Vec<Image> = patterns.iter().map(|(_, img)| img.clone()).collect();
+  draw_patterns(&images, 100., "patterns");
+  let Some((idx, img)) = patterns.get(selected) else {
+    return;
+  };
+  let (x, mut y) = (10., 40.);
+  draw_text(&format!("pattern {}", idx), x, y, 30., WHITE);
+  y += 10.;
+  draw_texture_ex(
+    Texture2D::from_image(img), x, y, WHITE,
+    DrawTextureParams {
+      dest_size: Some(Vec2::splat(TILE_SIZE * 4.)),
+      ..Default::default()
+    }
+  );
+  y += TILE_SIZE * 4. + 30.;
+  let mut directions: Vec<&(isize, isize)> = rules[*idx].keys().collect();
+  directions.sort();
+  for (dx, dy) in directions {
+    let neighbours: Vec<String> = rules[*idx][&(*dx, *dy)].iter().map(|n| n.to_string()).collect();
+    draw_text(&format!("{},{}: {}", dx, dy, neighbours.join(" ")), x, y, 20., WHITE);
+    y += 22.;
+  }
+}
+
+pub fn adjacency_to_dot(data: &AdjacencyData) -> String {
+  let mut dot = String::from("digraph adjacency {\n");
+  for idx in 0..data.len() {
+    dot.push_str(&format!("  {};\n", idx));
+  }
+  for (idx, adjacencies) in data.iter().enumerate() {
+    let mut directions: Vec<&(isize, isize)> = adjacencies.keys().collect();
+    directions.sort();
+    for (dx, dy) in directions {
+      for target_idx in adjacencies[&(*dx, *dy)].iter() {
+        dot.push_str(&format!("  {} -> {} [label=\"{},{}\"];\n", idx, target_idx, dx, dy));
+      }
+    }
+  }
+  dot.push_str("}\n");
+  dot
+}
+
+// learns which patterns may neighbour each other from an example layout (row-major pattern
+// indexes), pairs seen fewer than `min_observations` times are treated as accidents and forbidden
+pub fn learn_adjacency_from_layout(layout: &[usize], width: usize, pattern_count: usize, min_observations: usize) -> AdjacencyData {
+  let height = layout.len() / width;
+  let mut counts: HashMap<(usize, (isize, isize), usize), usize> = HashMap::new();
+  for (idx, pattern) in layout.iter().enumerate() {
+    for (dx, dy) in N_INDEXES {
+      let Some(neighbour_idx) = checked_neighbour(idx, dx, dy, width, height) else {
+        continue;
+      };
+      let neighbour = layout[neighbour_idx];
+      *counts.entry((*pattern, (dx, dy), neighbour)).or_insert(0) += 1;
+    }
+  }
+
+  (0..pattern_count)
+    .map(|pattern| {
+      N_INDEXES.iter()
+        .map(|direction| {
+          let allowed = (0..pattern_count)
+            .filter(|neighbour| counts.get(&(pattern, *direction, *neighbour)).copied().unwrap_or(0) >= min_observations.max(1))
+            .collect();
+          (*direction, allowed)
+        })
+        .collect()
+    })
+    .collect()
+}
+
+fn parse_direction(direction: &str, neighbourhood: &dyn Neighbourhood) -> Option<(isize, isize)> {
+  if let Some(named) = neighbourhood.direction_names().iter().position(|name| *name == direction) {
+    return Some(neighbourhood.offsets()[named]);
+  }
+  let (dx, dy) = direction.split_once(',')?;
+  Some((dx.trim().parse().ok()?, dy.trim().parse().ok()?))
+}
+
+// adjacency authored by hand for patterns that are just ids, one rule per line:
+//   <pattern> <up|right|down|left|dx,dy> <allowed neighbours...>
+// e.g. `0 right 1 2` lets patterns 1 and 2 sit right of pattern 0, `#` starts a comment. Every
+// pattern gets every direction used in the file (and the `N_INDEXES` ones), directions without
+// rules allow nothing. The rules have to be symmetric, whatever `a` allows at `d` must allow `a`
+// at `-d`
+pub fn parse_adjacency_rules(text: &str, pattern_count: usize) -> Result<AdjacencyData, RulesError> {
+  parse_adjacency_rules_for(text, pattern_count, &Square4)
+}
+
+// `parse_adjacency_rules` for another topology, directions are named by the neighbourhood's
+// `direction_names` (e.g. `east` or `northwest` for `Hex`) and every pattern gets its offsets
+pub fn parse_adjacency_rules_for(text: &str, pattern_count: usize, neighbourhood: &dyn Neighbourhood) -> Result<AdjacencyData, RulesError> {
+  let mut rules: AdjacencyData = vec![neighbourhood.offsets().iter().map(|d| (*d, vec![])).collect(); pattern_count];
+  for (line_idx, line) in text.lines().enumerate() {
+    let line_number = line_idx + 1;
+    let mut tokens = line.split('#').next().unwrap_or("").split_whitespace();
+    let Some(pattern) = tokens.next() else {
+      continue;
+    };
+    let pattern: usize = pattern.parse().map_err(|_| RulesError::Syntax { line: line_number })?;
+    let direction = tokens.next().and_then(|direction| parse_direction(direction, neighbourhood)).ok_or(RulesError::Syntax { line: line_number })?;
+    let neighbours = tokens
+      .map(|token| token.parse::<usize>().map_err(|_| RulesError::Syntax { line: line_number }))
+      .collect::<Result<Vec<usize>, RulesError>>()?;
+    if let Some(out_of_range) = std::iter::once(pattern).chain(neighbours.iter().copied()).find(|p| *p >= pattern_count) {
+      return Err(RulesError::OutOfRange { line: line_number, pattern: out_of_range });
+    }
+    for adjacencies in rules.iter_mut() {
+      adjacencies.entry(direction).or_default();
+    }
+    rules[pattern].get_mut(&direction).unwrap().extend(neighbours);
+  }
+  compact_adjacency(&mut rules, true);
+  for targets in rules.iter_mut().flat_map(|adjacencies| adjacencies.values_mut()) {
+    targets.dedup();
+  }
+  validate_adjacency(&rules).map_err(|errors| errors.into_iter().next().unwrap())?;
+  Ok(rules)
+}
+
+// every `RulesError::Asymmetric` in `data`: whatever a pattern allows at an offset has to allow
+// the pattern back at the negated offset, otherwise propagation gives different results depending
+// on which side a constraint comes from. Sorted by pattern, then direction
+pub fn validate_adjacency(data: &AdjacencyData) -> Result<(), Vec<RulesError>> {
+  let mut errors = vec![];
+  for (pattern, adjacencies) in data.iter().enumerate() {
+    let mut directions: Vec<&(isize, isize)> = adjacencies.keys().collect();
+    directions.sort_unstable();
+    for direction in directions {
+      let opposite = (-direction.0, -direction.1);
+      for neighbour in adjacencies[direction].iter() {
+        if !data.get(*neighbour).and_then(|rules| rules.get(&opposite)).is_some_and(|back| back.contains(&pattern)) {
+          errors.push(RulesError::Asymmetric { pattern, direction: *direction, neighbour: *neighbour });
+        }
+      }
+    }
+  }
+  if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+// `patterns` as a bitset sized for `pattern_count` patterns
+pub fn pattern_set(pattern_count: usize, patterns: impl IntoIterator<Item = usize>) -> FixedBitSet {
+  let mut set = FixedBitSet::with_capacity(pattern_count);
+  set.extend(patterns);
+  set
+}
+
+// the adjacency with every neighbour list as a bitset, so constraining a cell is an intersection
+pub fn adjacency_to_bitsets(data: &AdjacencyData, pattern_count: usize) -> AdjacencyBits {
+  data.iter()
+    .map(|adjacencies| adjacencies.iter().map(|(direction, targets)| (*direction, pattern_set(pattern_count, targets.iter().copied()))).collect())
+    .collect()
+}
+
+// dense form of the adjacency, `matrices[direction][a][b]` is true iff `b` may sit at
+// `direction` from `a`
+pub fn adjacency_to_matrices(data: &AdjacencyData, pattern_count: usize) -> HashMap<(isize, isize), Vec<Vec<bool>>> {
+  let mut matrices: HashMap<(isize, isize), Vec<Vec<bool>>> = HashMap::new();
+  for (idx, adjacencies) in data.iter().enumerate() {
+    for (direction, targets) in adjacencies.iter() {
+      let matrix = matrices.entry(*direction).or_insert_with(|| vec![vec![false; pattern_count]; pattern_count]);
+      for target_idx in targets {
+        matrix[idx][*target_idx] = true;
+      }
+    }
+  }
+  matrices
+}
+
+// drops the spare capacity left over from push-based construction, `sort` additionally orders
+// every neighbour list ascending
+pub fn compact_adjacency(data: &mut AdjacencyData, sort: bool) {
+  for adjacencies in data.iter_mut() {
+    for targets in adjacencies.values_mut() {
+      if sort {
+        targets.sort_unstable();
+      }
+      targets.shrink_to_fit();
+    }
+    adjacencies.shrink_to_fit();
+  }
+  data.shrink_to_fit();
+}
+
+// groups of patterns with the same neighbour sets in every direction, both the patterns they allow
+// and the patterns allowing them, such patterns are interchangeable for the solver
+pub fn find_equivalent_patterns(data: &AdjacencyData) -> Vec<Vec<usize>> {
+  let mut incoming: Vec<HashMap<(isize, isize), Vec<usize>>> = vec![HashMap::new(); data.len()];
+  for (idx, adjacencies) in data.iter().enumerate() {
+    for (direction, targets) in adjacencies.iter() {
+      for target_idx in targets {
+        incoming[*target_idx].entry(*direction).or_default().push(idx);
+      }
+    }
+  }
+  let signature = |neighbours: &HashMap<(isize, isize), Vec<usize>>| {
+    let mut signature: Vec<((isize, isize), Vec<usize>)> = neighbours.iter()
+      .filter(|(_, targets)| !targets.is_empty())
+      .map(|(direction, targets)| {
+        let mut targets = targets.clone();
+        targets.sort_unstable();
+        targets.dedup();
+        (*direction, targets)
+      })
+      .collect();
+    signature.sort();
+    signature
+  };
+
+  let mut groups: Vec<Vec<usize>> = vec![];
+  let mut group_signatures = vec![];
+  for idx in 0..data.len() {
+    let pattern_signature = (signature(&data[idx]), signature(&incoming[idx]));
+    if let Some(group_idx) = group_signatures.iter().position(|s| *s == pattern_signature) {
+      groups[group_idx].push(idx);
+    } else {
+      group_signatures.push(pattern_signature);
+      groups.push(vec![idx]);
+    }
+  }
+  groups
+}
+
+// adjacency between `groups` (e.g. from `find_equivalent_patterns`), pattern `i` of the result
+// stands for every pattern in `groups[i]`, which can serve as its render variants
+pub fn merge_equivalent_patterns(data: &AdjacencyData, groups: &[Vec<usize>]) -> AdjacencyData {
+  let mut group_of = vec![0; data.len()];
+  for (group_idx, group) in groups.iter().enumerate() {
+    for idx in group {
+      group_of[*idx] = group_idx;
+    }
+  }
+  groups.iter()
+    .map(|group| {
+      data[group[0]].iter()
+        .map(|(direction, targets)| {
+          let mut merged: Vec<usize> = targets.iter().map(|t| group_of[*t]).collect();
+          merged.sort_unstable();
+          merged.dedup();
+          (*direction, merged)
+        })
+        .collect()
+    })
+    .collect()
+}
+
+// pattern weights for the result of `merge_equivalent_patterns`, a merged pattern is picked as
+// often as all of its group together
+pub fn merge_equivalent_weights(weights: &[f32], groups: &[Vec<usize>]) -> Vec<f32> {
+  groups.iter().map(|group| group.iter().map(|idx| weights[*idx]).sum()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{rc::Rc, sync::Mutex};
+
+  use super::*;
+
+  #[test]
+  fn weighted_choice_never_picks_zero_weights() {
+    let rng = SeededRng::new(0);
+    let weights = [0., 2., 0., 1.];
+    for _ in 0..1000 {
+      assert!(matches!(weighted_choice(&rng, &[0, 1, 2, 3], |p| weights[p]), Some(1) | Some(3)));
+    }
+    assert_eq!(weighted_choice(&rng, &[0, 2], |p| weights[p]), None);
+  }
+
+  #[test]
+  fn adjacency_as_dot() {
+    let data = crate::tests::rules(2, &[(1, 0), (-1, 0)], |p| vec![p]);
+    assert_eq!(adjacency_to_dot(&data), "digraph adjacency {\n  0;\n  1;\n  0 -> 0 [label=\"-1,0\"];\n  0 -> 0 [label=\"1,0\"];\n  1 -> 1 [label=\"-1,0\"];\n  1 -> 1 [label=\"1,0\"];\n}\n");
+  }
+
+  #[test]
+  fn learning_drops_rare_pairs() {
+    // the 2 in the corner is the only one, so is its pairing with 1
+    let layout = [
+      0, 0, 0, 0,
+      0, 0, 0, 0,
+      1, 1, 1, 1,
+      1, 1, 1, 2,
+    ];
+    let learned = learn_adjacency_from_layout(&layout, 4, 3, 2);
+    assert_eq!(learned[1][&(1, 0)], vec![1]);
+    assert!(learned[2].values().all(|allowed| allowed.is_empty()));
+    assert_eq!(learned[0][&(0, 1)], vec![0, 1]);
+    // never seen together stays forbidden with a single observation
+    let learned = learn_adjacency_from_layout(&layout, 4, 3, 1);
+    assert_eq!(learned[1][&(1, 0)], vec![1, 2]);
+    assert!(!learned[0][&(0, 1)].contains(&2));
+  }
+
+  #[test]
+  fn adjacency_as_matrices() {
+    let data = crate::tests::three_bands();
+    let matrices = adjacency_to_matrices(&data, 3);
+    assert_eq!(matrices.len(), N_INDEXES.len());
+    for (direction, matrix) in matrices.iter() {
+      for (a, row) in matrix.iter().enumerate() {
+        for (b, allowed) in row.iter().enumerate() {
+          assert_eq!(*allowed, data[a][direction].contains(&b));
+        }
+      }
+    }
+    assert_eq!(matrices[&(1, 0)][0], vec![true, true, false]);
+  }
+
+  #[test]
+  fn compacts_adjacency() {
+    let mut data: AdjacencyData = Vec::with_capacity(16);
+    for p in 0..3 {
+      let mut adjacencies = HashMap::new();
+      for direction in N_INDEXES {
+        let mut targets = Vec::with_capacity(32);
+        targets.extend((0..3).rev().filter(|target| *target != p));
+        adjacencies.insert(direction, targets);
+      }
+      data.push(adjacencies);
+    }
+    compact_adjacency(&mut data, true);
+    assert_eq!(data.capacity(), data.len());
+    for targets in data.iter().flat_map(|adjacencies| adjacencies.values()) {
+      assert_eq!(targets.capacity(), targets.len());
+      assert!(targets.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+  }
+
+  #[test]
+  fn reports_progress_every_few_and_at_the_end() {
+    let reported = Arc::new(Mutex::new(vec![]));
+    let sink = Arc::clone(&reported);
+    let progress: Option<(ProgressCallback, usize)> = Some((Arc::new(move |done, total| sink.lock().unwrap().push((done, total))), 3));
+    let done = AtomicUsize::new(0);
+    for _ in 0..7 {
+      report_progress(&progress, &done, 7);
+    }
+    assert_eq!(*reported.lock().unwrap(), vec![(3, 7), (6, 7), (7, 7)]);
+  }
+
+  #[test]
+  fn rotates_non_square_images() {
+    let mut image = Image::gen_image_color(2, 3, WHITE);
+    image.set_pixel(0, 0, BLACK);
+    image.set_pixel(1, 2, RED);
+    // a clockwise quarter turn takes x, y to height - 1 - y, x
+    let turned = rotate_image(&image, 1);
+    assert_eq!((turned.width, turned.height), (3, 2));
+    assert_eq!(turned.get_pixel(2, 0), image.get_pixel(0, 0));
+    assert_eq!(turned.get_pixel(0, 1), image.get_pixel(1, 2));
+    let half = rotate_image(&image, 2);
+    assert_eq!((half.width, half.height), (2, 3));
+    assert_eq!(half.get_pixel(1, 2), image.get_pixel(0, 0));
+    assert_eq!(rotate_image(&image, 4).bytes, image.bytes);
+  }
+
+  #[test]
+  fn reflects_images() {
+    let mut image = Image::gen_image_color(3, 2, WHITE);
+    image.set_pixel(0, 0, BLACK);
+    assert_eq!(reflect_image(&image, Axis::Vertical).get_pixel(2, 0), image.get_pixel(0, 0));
+    assert_eq!(reflect_image(&image, Axis::Horizontal).get_pixel(0, 1), image.get_pixel(0, 0));
+    for axis in [Axis::Vertical, Axis::Horizontal] {
+      assert_eq!(reflect_image(&reflect_image(&image, axis), axis).bytes, image.bytes);
+    }
+  }
+
+  #[test]
+  fn checked_neighbours_at_corners_and_centre() {
+    let (width, height) = (4, 3);
+    // every neighbour of every corner, the ones pointing off the grid are `None`
+    for (idx, (x, y)) in [(0, (0, 0)), (3, (3, 0)), (8, (0, 2)), (11, (3, 2))] {
+      for (dx, dy) in N_INDEXES_DIAGONAL {
+        let (nx, ny) = (x + dx, y + dy);
+        let inside = (0..width as isize).contains(&nx) && (0..height as isize).contains(&ny);
+        let expected = inside.then(|| index_from_xy(nx as usize, ny as usize, width));
+        assert_eq!(checked_neighbour(idx, dx, dy, width, height), expected, "{}, {} from {}", dx, dy, idx);
+      }
+    }
+    // the centre has all eight
+    let around: Vec<Option<usize>> = N_INDEXES_DIAGONAL.iter().map(|(dx, dy)| checked_neighbour(5, *dx, *dy, width, height)).collect();
+    assert_eq!(around, [1, 2, 6, 10, 9, 8, 4, 0].map(Some));
+  }
+
+  #[test]
+  fn merges_equivalent_patterns() {
+    // 0 and 1 both sit next to 0, 1 and 2 and only those sit next to them, 2 also borders 3
+    let data = crate::tests::rules(4, &N_INDEXES, |p| match p {
+      0 | 1 => vec![0, 1, 2],
+      2 => vec![0, 1, 2, 3],
+      _ => vec![2, 3],
+    });
+    let groups = find_equivalent_patterns(&data);
+    assert_eq!(groups, vec![vec![0, 1], vec![2], vec![3]]);
+    let merged = merge_equivalent_patterns(&data, &groups);
+    let expected = crate::tests::rules(3, &N_INDEXES, |p| match p {
+      0 => vec![0, 1],
+      1 => vec![0, 1, 2],
+      _ => vec![1, 2],
+    });
+    assert_eq!(merged, *expected);
+    assert_eq!(merge_equivalent_weights(&[0.1, 0.2, 0.3, 0.4], &groups), vec![0.1 + 0.2, 0.3, 0.4]);
+    // the merged rules solve as well as the original ones
+    for seed in 0..5 {
+      let mut grid = crate::Grid::new_with_seed(8, 8, &Rc::new(merged.clone()), &Rc::new(crate::tests::Ids(3)), seed)
+        .with_pattern_weights(merge_equivalent_weights(&[0.1, 0.2, 0.3, 0.4], &groups));
+      grid.run().unwrap();
+      assert!(crate::tests::is_valid(&grid));
+    }
+  }
+
+  #[test]
+  fn parses_adjacency_rules() {
+    let text = "
+      # 0 and 1 side by side, anything stacked
+      0 right 1
+      1 left 0  # back the other way
+      0 up 0 1
+      1 up 0 1
+      0 down 0 1
+      1 down 0 1
+      0 1,1 0
+      0 -1,-1 0
+    ";
+    let rules = parse_adjacency_rules(text, 2).unwrap();
+    assert_eq!(rules[0][&(1, 0)], vec![1]);
+    assert_eq!(rules[1][&(-1, 0)], vec![0]);
+    assert_eq!(rules[0][&(-1, 0)], Vec::<usize>::new());
+    assert_eq!(rules[1][&(0, -1)], vec![0, 1]);
+    // a direction given as an offset shows up for every pattern, allowing nothing where unlisted
+    assert_eq!(rules[0][&(1, 1)], vec![0]);
+    assert_eq!(rules[1][&(1, 1)], Vec::<usize>::new());
+  }
+
+  #[test]
+  fn rejects_malformed_and_asymmetric_rules() {
+    let cases = [
+      ("0 right 1\nzero left 1", RulesError::Syntax { line: 2 }),
+      ("0 sideways 1", RulesError::Syntax { line: 1 }),
+      ("0 1,x 1", RulesError::Syntax { line: 1 }),
+      ("0", RulesError::Syntax { line: 1 }),
+      ("0 right one", RulesError::Syntax { line: 1 }),
+      ("\n0 right 2", RulesError::OutOfRange { line: 2, pattern: 2 }),
+      ("0 right 1", RulesError::Asymmetric { pattern: 0, direction: (1, 0), neighbour: 1 }),
+    ];
+    for (text, error) in cases {
+      assert_eq!(parse_adjacency_rules(text, 2), Err(error), "{:?}", text);
+    }
+  }
+}
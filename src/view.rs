@@ -0,0 +1,53 @@
+use macroquad::prelude::*;
+
+use crate::{TILE_SIZE, GRID_OFFSET};
+
+const MIN_ZOOM: f32 = 0.05;
+const MAX_ZOOM: f32 = 16.;
+
+// pan and zoom over a grid, so it can be any size regardless of the window. `offset` is the grid
+// pixel at the top left of the screen and `zoom` the screen pixels per grid pixel
+#[derive(Clone, Copy, Debug)]
+pub struct View {
+  pub offset: Vec2,
+  pub zoom: f32,
+}
+
+impl View {
+  // the whole `width` x `height` cells grid centered on screen, as large as it fits
+  pub fn fit(width: usize, height: usize) -> Self {
+    let size = vec2(width as f32, height as f32) * TILE_SIZE;
+    let zoom = (screen_width() / size.x).min(screen_height() / size.y).clamp(MIN_ZOOM, MAX_ZOOM);
+    let offset = vec2(GRID_OFFSET, GRID_OFFSET) + (size - vec2(screen_width(), screen_height()) / zoom) / 2.;
+    Self { offset, zoom }
+  }
+
+  // moves the grid along with a drag of `delta` screen pixels
+  pub fn pan(&mut self, delta: Vec2) {
+    self.offset -= delta / self.zoom;
+  }
+
+  // scales by `factor` keeping the grid pixel under the screen position `anchor` in place
+  pub fn zoom_at(&mut self, factor: f32, anchor: Vec2) {
+    let anchored = self.screen_to_grid(anchor);
+    self.zoom = (self.zoom * factor).clamp(MIN_ZOOM, MAX_ZOOM);
+    self.offset = anchored - anchor / self.zoom;
+  }
+
+  pub fn screen_to_grid(&self, position: Vec2) -> Vec2 {
+    self.offset + position / self.zoom
+  }
+
+  // set with `set_camera` before `Grid::draw` (or `draw_viewport`), drawing in screen space
+  // afterwards needs `set_default_camera`
+  pub fn camera(&self) -> Camera2D {
+    Camera2D::from_display_rect(Rect::new(self.offset.x, self.offset.y, screen_width() / self.zoom, screen_height() / self.zoom))
+  }
+
+  // the cells on screen, for `Grid::draw_viewport`
+  pub fn visible_cells(&self) -> Rect {
+    let origin = (self.offset - vec2(GRID_OFFSET, GRID_OFFSET)) / TILE_SIZE;
+    let size = vec2(screen_width(), screen_height()) / self.zoom / TILE_SIZE;
+    Rect::new(origin.x, origin.y, size.x, size.y)
+  }
+}